@@ -0,0 +1,19 @@
+use ibverbs_rs::list_devices;
+
+fn main() -> anyhow::Result<()> {
+    ibverbs_rs::initialize_logger();
+    let devices = list_devices()?;
+    println!("{:<16} {:<20} {:<28} {:<12} {:<16} {}", "device", "node GUID", "node type", "transport", "firmware", "ports");
+    for device in devices {
+        println!(
+            "{:<16} {:<20} {:<28} {:<12} {:<16} {}",
+            device.name,
+            device.node_guid_string(),
+            device.node_type.to_string(),
+            device.transport.to_string(),
+            device.fw_ver,
+            device.phys_port_cnt,
+        );
+    }
+    Ok(())
+}
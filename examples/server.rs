@@ -26,19 +26,20 @@ fn main() -> anyhow::Result<()> {
     )?;
     let hints = Hints::Address(address.parse().unwrap());
     receiver.listen(hints)?;
-    //let flags = IbvAccessFlags::LocalWrite.as_i32() | IbvAccessFlags::RemoteWrite.as_i32() | IbvAccessFlags::RemoteRead.as_i32();
-    //let mr = IbvMr::new(&receiver.pd, buf.as_ptr() as *mut u8, buf.len(), flags as i32);
+    //let flags = IbvAccessFlags::local_and_remote();
+    //let mr = IbvMr::from_vec(&receiver.pd, buf, flags)?;
     let sge = IbvSge::new(receiver.metadata_addr(), MrMetadata::SIZE as u32, receiver.metadata_lkey());
-    let notify_wr = IbvRecvWr::new(0,sge,1);
+    let notify_wr = IbvRecvWr::new(0, vec![sge]);
     info!("Posting receive");
-    receiver.qp_list[0].ibv_post_recv(notify_wr)?;
+    receiver.init_qp_list[0].post_recv(notify_wr)?;
     receiver.connect()?;
     info!("Receiver connected all QPs");
-    receiver.qp_list[0].state()?;
+    let qp = receiver.qp(0).ok_or_else(|| anyhow::anyhow!("QP 0 is not connected"))?;
+    qp.state()?;
 
 
     info!("Waiting for event");
-    receiver.qp_list[0].wait_for_event()?;
+    qp.wait_for_event()?;
     info!("Event received");
     Ok(())
 
@@ -0,0 +1,377 @@
+use std::{fmt, ptr};
+use rdma_sys::*;
+
+use crate::{device_name, IbvError, IbvQp, IbvRecvWr, IbvSendWr, IbvSrq};
+
+/// Send WRs kept in one vector and posted with a single `ibv_post_send`,
+/// so the device is notified once for the whole batch.
+#[derive(Default)]
+pub struct SendBatch{
+    wrs: Vec<IbvSendWr>,
+}
+
+impl SendBatch{
+    pub fn new() -> Self{
+        SendBatch::default()
+    }
+    pub fn with_capacity(capacity: usize) -> Self{
+        SendBatch{
+            wrs: Vec::with_capacity(capacity),
+        }
+    }
+    /// Appends `wr` and any WRs chained to it with `set_next`.
+    pub fn push(&mut self, mut wr: IbvSendWr) -> &mut Self{
+        loop{
+            let next = wr.next.take();
+            wr.inner.next = ptr::null_mut();
+            self.wrs.push(wr);
+            match next{
+                Some(next) => wr = *next,
+                None => break,
+            }
+        }
+        self
+    }
+    pub fn len(&self) -> usize{
+        self.wrs.len()
+    }
+    pub fn is_empty(&self) -> bool{
+        self.wrs.is_empty()
+    }
+    pub fn wrs(&self) -> &[IbvSendWr]{
+        &self.wrs
+    }
+    pub fn into_wrs(self) -> Vec<IbvSendWr>{
+        self.wrs
+    }
+}
+
+impl From<Vec<IbvSendWr>> for SendBatch{
+    fn from(wrs: Vec<IbvSendWr>) -> Self{
+        let mut batch = SendBatch::with_capacity(wrs.len());
+        for wr in wrs{
+            batch.push(wr);
+        }
+        batch
+    }
+}
+
+impl fmt::Debug for SendBatch{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        f.debug_list().entries(self.wrs.iter().map(|wr| wr.wr_id())).finish()
+    }
+}
+
+/// Receive WRs posted with a single `ibv_post_recv` or
+/// `ibv_post_srq_recv`, see `SendBatch`.
+#[derive(Default)]
+pub struct RecvBatch{
+    wrs: Vec<IbvRecvWr>,
+}
+
+impl RecvBatch{
+    pub fn new() -> Self{
+        RecvBatch::default()
+    }
+    pub fn with_capacity(capacity: usize) -> Self{
+        RecvBatch{
+            wrs: Vec::with_capacity(capacity),
+        }
+    }
+    /// Appends `wr` and any WRs chained to it with `set_next`.
+    pub fn push(&mut self, mut wr: IbvRecvWr) -> &mut Self{
+        loop{
+            let next = wr.next.take();
+            wr.inner.next = ptr::null_mut();
+            self.wrs.push(wr);
+            match next{
+                Some(next) => wr = *next,
+                None => break,
+            }
+        }
+        self
+    }
+    pub fn len(&self) -> usize{
+        self.wrs.len()
+    }
+    pub fn is_empty(&self) -> bool{
+        self.wrs.is_empty()
+    }
+    pub fn wrs(&self) -> &[IbvRecvWr]{
+        &self.wrs
+    }
+    pub fn into_wrs(self) -> Vec<IbvRecvWr>{
+        self.wrs
+    }
+}
+
+impl From<Vec<IbvRecvWr>> for RecvBatch{
+    fn from(wrs: Vec<IbvRecvWr>) -> Self{
+        let mut batch = RecvBatch::with_capacity(wrs.len());
+        for wr in wrs{
+            batch.push(wr);
+        }
+        batch
+    }
+}
+
+impl fmt::Debug for RecvBatch{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        f.debug_list().entries(self.wrs.iter().map(|wr| wr.wr_id())).finish()
+    }
+}
+
+/// Returned when a batch was only partly posted. The WRs before
+/// `bad_index` were accepted and will complete; `unposted` holds the rest,
+/// starting with the rejected one, so they can be fixed and posted again.
+pub struct PostError<B>{
+    pub error: IbvError,
+    pub bad_index: usize,
+    pub unposted: B,
+}
+
+impl<B> fmt::Debug for PostError<B>{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        f.debug_struct("PostError")
+            .field("error", &self.error)
+            .field("bad_index", &self.bad_index)
+            .finish()
+    }
+}
+
+impl<B> fmt::Display for PostError<B>{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "work request {} of the batch was rejected: {}", self.bad_index, self.error)
+    }
+}
+
+impl<B> std::error::Error for PostError<B>{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>{
+        Some(&self.error)
+    }
+}
+
+pub type PostResult<B> = std::result::Result<(), PostError<B>>;
+
+// What `post` needs to know about a WR type.
+trait BatchWr{
+    type Raw;
+    fn raw(&mut self) -> *mut Self::Raw;
+    fn link(&mut self, next: *mut Self::Raw);
+    fn num_sge(&self) -> usize;
+    fn wr_id(&self) -> u64;
+}
+
+impl BatchWr for IbvSendWr{
+    type Raw = ibv_send_wr;
+    fn raw(&mut self) -> *mut ibv_send_wr{
+        &mut self.inner
+    }
+    fn link(&mut self, next: *mut ibv_send_wr){
+        self.inner.next = next;
+    }
+    fn num_sge(&self) -> usize{
+        self.sg_list.len()
+    }
+    fn wr_id(&self) -> u64{
+        self.inner.wr_id
+    }
+}
+
+impl BatchWr for IbvRecvWr{
+    type Raw = ibv_recv_wr;
+    fn raw(&mut self) -> *mut ibv_recv_wr{
+        &mut self.inner
+    }
+    fn link(&mut self, next: *mut ibv_recv_wr){
+        self.inner.next = next;
+    }
+    fn num_sge(&self) -> usize{
+        self.sg_list.len()
+    }
+    fn wr_id(&self) -> u64{
+        self.inner.wr_id
+    }
+}
+
+// Links the WRs in front of the first one with more than `max_sge` SGEs,
+// hands the head to `post_fn` and maps a failure of either kind to the
+// index of the rejected WR and the error. On error `wrs` is cut down to
+// the WRs that were posted and the rest is returned.
+fn post<W: BatchWr>(
+    wrs: &mut Vec<W>,
+    max_sge: u32,
+    post_fn: impl FnOnce(*mut W::Raw, &mut *mut W::Raw) -> i32,
+    error: impl FnOnce(i32) -> IbvError,
+) -> std::result::Result<(), (IbvError, usize, Vec<W>)>{
+    let oversized = wrs.iter().position(|wr| wr.num_sge() > max_sge as usize);
+    let count = oversized.unwrap_or(wrs.len());
+    let mut ret = 0;
+    let mut bad_wr = ptr::null_mut();
+    if count > 0 {
+        // The vector is not touched again until the call returns, so the
+        // pointers stay valid.
+        for idx in (0..count).rev(){
+            let next = if idx + 1 < count { wrs[idx + 1].raw() } else { ptr::null_mut() };
+            wrs[idx].link(next);
+        }
+        ret = post_fn(wrs[0].raw(), &mut bad_wr);
+    }
+    let (bad_index, ret) = if ret != 0 {
+        let bad_index = wrs.iter_mut().position(|wr| ptr::eq(wr.raw(), bad_wr));
+        (bad_index.unwrap_or(0), ret)
+    } else if let Some(idx) = oversized {
+        (idx, libc::EINVAL)
+    } else {
+        return Ok(());
+    };
+    for wr in wrs.iter_mut(){
+        wr.link(ptr::null_mut());
+    }
+    let unposted = wrs.split_off(bad_index);
+    let error = error(ret).with_wr_id(unposted[0].wr_id());
+    Err((error, bad_index, unposted))
+}
+
+impl IbvQp{
+    /// Posts all WRs of `batch` with one `ibv_post_send`. A WR with more
+    /// SGEs than the QP was granted is rejected like the device would,
+    /// after posting the ones in front of it.
+    pub fn post_send_batch(&self, batch: SendBatch) -> PostResult<SendBatch>{
+        let mut wrs = batch.wrs;
+        post(&mut wrs, self.cap.max_send_sge, |wr, bad_wr| unsafe{ ibv_post_send(self.as_ptr(), wr, bad_wr) }, |ret| self.error("ibv_post_send", ret))
+            .map_err(|(error, bad_index, unposted)| PostError{
+                error,
+                bad_index,
+                unposted: SendBatch{ wrs: unposted },
+            })
+    }
+    /// Posts all WRs of `batch` with one `ibv_post_recv`.
+    pub fn post_recv_batch(&self, batch: RecvBatch) -> PostResult<RecvBatch>{
+        let mut wrs = batch.wrs;
+        post(&mut wrs, self.cap.max_recv_sge, |wr, bad_wr| unsafe{ ibv_post_recv(self.as_ptr(), wr, bad_wr) }, |ret| self.error("ibv_post_recv", ret))
+            .map_err(|(error, bad_index, unposted)| PostError{
+                error,
+                bad_index,
+                unposted: RecvBatch{ wrs: unposted },
+            })
+    }
+}
+
+impl IbvSrq{
+    /// Posts all WRs of `batch` with one `ibv_post_srq_recv`.
+    pub fn post_recv_batch(&self, batch: RecvBatch) -> PostResult<RecvBatch>{
+        let context = unsafe{ (*self.as_ptr()).context };
+        let mut wrs = batch.wrs;
+        post(&mut wrs, self.max_sge(), |wr, bad_wr| unsafe{ ibv_post_srq_recv(self.as_ptr(), wr, bad_wr) }, |ret| IbvError::from_ret("ibv_post_srq_recv", ret).with_device(device_name(context)))
+            .map_err(|(error, bad_index, unposted)| PostError{
+                error,
+                bad_index,
+                unposted: RecvBatch{ wrs: unposted },
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    struct FakeRaw{
+        id: u64,
+        next: *mut FakeRaw,
+    }
+
+    struct FakeWr{
+        raw: FakeRaw,
+        num_sge: usize,
+    }
+
+    impl BatchWr for FakeWr{
+        type Raw = FakeRaw;
+        fn raw(&mut self) -> *mut FakeRaw{
+            &mut self.raw
+        }
+        fn link(&mut self, next: *mut FakeRaw){
+            self.raw.next = next;
+        }
+        fn num_sge(&self) -> usize{
+            self.num_sge
+        }
+        fn wr_id(&self) -> u64{
+            self.raw.id
+        }
+    }
+
+    fn wrs(num_sges: &[usize]) -> Vec<FakeWr>{
+        num_sges.iter().enumerate().map(|(id, &num_sge)| FakeWr{
+            raw: FakeRaw{ id: id as u64, next: ptr::null_mut() },
+            num_sge,
+        }).collect()
+    }
+
+    // The ids of the chain starting at `wr`.
+    fn chain(mut wr: *mut FakeRaw) -> Vec<u64>{
+        let mut ids = Vec::new();
+        while !wr.is_null() {
+            unsafe{
+                ids.push((*wr).id);
+                wr = (*wr).next;
+            }
+        }
+        ids
+    }
+
+    fn error(ret: i32) -> IbvError{
+        IbvError::from_ret("ibv_post_send", ret)
+    }
+
+    #[test]
+    fn posts_the_whole_chain(){
+        let mut batch = wrs(&[1, 2, 1]);
+        let mut posted = Vec::new();
+        let result = post(&mut batch, 2, |wr, _| {
+            posted = chain(wr);
+            0
+        }, error);
+        assert!(result.is_ok());
+        assert_eq!(posted, [0, 1, 2]);
+    }
+
+    #[test]
+    fn splits_at_the_rejected_wr(){
+        let mut batch = wrs(&[1, 1, 1, 1]);
+        let (err, bad_index, unposted) = post(&mut batch, 1, |wr, bad_wr| {
+            unsafe{ *bad_wr = (*(*wr).next).next };
+            libc::ENOMEM
+        }, error).unwrap_err();
+        assert_eq!(bad_index, 2);
+        assert_eq!(err.errno(), Some(libc::ENOMEM));
+        assert_eq!(batch.iter().map(|wr| wr.wr_id()).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(unposted.iter().map(|wr| wr.wr_id()).collect::<Vec<_>>(), [2, 3]);
+        assert!(batch.iter().chain(&unposted).all(|wr| wr.raw.next.is_null()));
+    }
+
+    #[test]
+    fn stops_before_an_oversized_wr(){
+        let mut batch = wrs(&[1, 1, 3, 1]);
+        let mut posted = Vec::new();
+        let (err, bad_index, unposted) = post(&mut batch, 2, |wr, _| {
+            posted = chain(wr);
+            0
+        }, error).unwrap_err();
+        assert_eq!(posted, [0, 1]);
+        assert_eq!(bad_index, 2);
+        assert_eq!(err.errno(), Some(libc::EINVAL));
+        assert_eq!(unposted.len(), 2);
+        assert_eq!(batch.len(), 2);
+    }
+
+    #[test]
+    fn oversized_first_wr_posts_nothing(){
+        let mut batch = wrs(&[3, 1]);
+        let (_, bad_index, unposted) = post(&mut batch, 2, |_, _| panic!("nothing to post"), error).unwrap_err();
+        assert_eq!(bad_index, 0);
+        assert_eq!(unposted.len(), 2);
+        assert!(batch.is_empty());
+    }
+}
@@ -0,0 +1,289 @@
+use std::{ffi::CStr, fmt};
+use bitflags::bitflags;
+use rdma_sys::*;
+
+use crate::IbvError;
+
+/// The status of a work completion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WcStatus{
+    Success,
+    LocLenErr,
+    LocQpOpErr,
+    LocEecOpErr,
+    LocProtErr,
+    WrFlushErr,
+    MwBindErr,
+    BadRespErr,
+    LocAccessErr,
+    RemInvReqErr,
+    RemAccessErr,
+    RemOpErr,
+    RetryExcErr,
+    RnrRetryExcErr,
+    LocRddViolErr,
+    RemInvRdReqErr,
+    RemAbortErr,
+    InvEecnErr,
+    InvEecStateErr,
+    FatalErr,
+    RespTimeoutErr,
+    GeneralErr,
+    TmErr,
+    TmRndvIncomplete,
+    Unknown(u32),
+}
+
+impl WcStatus{
+    pub fn get(&self) -> ibv_wc_status::Type{
+        match self{
+            WcStatus::Success => ibv_wc_status::IBV_WC_SUCCESS,
+            WcStatus::LocLenErr => ibv_wc_status::IBV_WC_LOC_LEN_ERR,
+            WcStatus::LocQpOpErr => ibv_wc_status::IBV_WC_LOC_QP_OP_ERR,
+            WcStatus::LocEecOpErr => ibv_wc_status::IBV_WC_LOC_EEC_OP_ERR,
+            WcStatus::LocProtErr => ibv_wc_status::IBV_WC_LOC_PROT_ERR,
+            WcStatus::WrFlushErr => ibv_wc_status::IBV_WC_WR_FLUSH_ERR,
+            WcStatus::MwBindErr => ibv_wc_status::IBV_WC_MW_BIND_ERR,
+            WcStatus::BadRespErr => ibv_wc_status::IBV_WC_BAD_RESP_ERR,
+            WcStatus::LocAccessErr => ibv_wc_status::IBV_WC_LOC_ACCESS_ERR,
+            WcStatus::RemInvReqErr => ibv_wc_status::IBV_WC_REM_INV_REQ_ERR,
+            WcStatus::RemAccessErr => ibv_wc_status::IBV_WC_REM_ACCESS_ERR,
+            WcStatus::RemOpErr => ibv_wc_status::IBV_WC_REM_OP_ERR,
+            WcStatus::RetryExcErr => ibv_wc_status::IBV_WC_RETRY_EXC_ERR,
+            WcStatus::RnrRetryExcErr => ibv_wc_status::IBV_WC_RNR_RETRY_EXC_ERR,
+            WcStatus::LocRddViolErr => ibv_wc_status::IBV_WC_LOC_RDD_VIOL_ERR,
+            WcStatus::RemInvRdReqErr => ibv_wc_status::IBV_WC_REM_INV_RD_REQ_ERR,
+            WcStatus::RemAbortErr => ibv_wc_status::IBV_WC_REM_ABORT_ERR,
+            WcStatus::InvEecnErr => ibv_wc_status::IBV_WC_INV_EECN_ERR,
+            WcStatus::InvEecStateErr => ibv_wc_status::IBV_WC_INV_EEC_STATE_ERR,
+            WcStatus::FatalErr => ibv_wc_status::IBV_WC_FATAL_ERR,
+            WcStatus::RespTimeoutErr => ibv_wc_status::IBV_WC_RESP_TIMEOUT_ERR,
+            WcStatus::GeneralErr => ibv_wc_status::IBV_WC_GENERAL_ERR,
+            WcStatus::TmErr => ibv_wc_status::IBV_WC_TM_ERR,
+            WcStatus::TmRndvIncomplete => ibv_wc_status::IBV_WC_TM_RNDV_INCOMPLETE,
+            WcStatus::Unknown(status) => *status,
+        }
+    }
+    pub fn is_success(&self) -> bool{
+        *self == WcStatus::Success
+    }
+    /// The description libibverbs gives for the status.
+    pub fn as_str(&self) -> &'static str{
+        let s = unsafe{ ibv_wc_status_str(self.get()) };
+        if s.is_null() {
+            return "unknown";
+        }
+        unsafe{ CStr::from_ptr(s) }.to_str().unwrap_or("unknown")
+    }
+}
+
+impl From<ibv_wc_status::Type> for WcStatus{
+    fn from(status: ibv_wc_status::Type) -> Self{
+        match status{
+            ibv_wc_status::IBV_WC_SUCCESS => WcStatus::Success,
+            ibv_wc_status::IBV_WC_LOC_LEN_ERR => WcStatus::LocLenErr,
+            ibv_wc_status::IBV_WC_LOC_QP_OP_ERR => WcStatus::LocQpOpErr,
+            ibv_wc_status::IBV_WC_LOC_EEC_OP_ERR => WcStatus::LocEecOpErr,
+            ibv_wc_status::IBV_WC_LOC_PROT_ERR => WcStatus::LocProtErr,
+            ibv_wc_status::IBV_WC_WR_FLUSH_ERR => WcStatus::WrFlushErr,
+            ibv_wc_status::IBV_WC_MW_BIND_ERR => WcStatus::MwBindErr,
+            ibv_wc_status::IBV_WC_BAD_RESP_ERR => WcStatus::BadRespErr,
+            ibv_wc_status::IBV_WC_LOC_ACCESS_ERR => WcStatus::LocAccessErr,
+            ibv_wc_status::IBV_WC_REM_INV_REQ_ERR => WcStatus::RemInvReqErr,
+            ibv_wc_status::IBV_WC_REM_ACCESS_ERR => WcStatus::RemAccessErr,
+            ibv_wc_status::IBV_WC_REM_OP_ERR => WcStatus::RemOpErr,
+            ibv_wc_status::IBV_WC_RETRY_EXC_ERR => WcStatus::RetryExcErr,
+            ibv_wc_status::IBV_WC_RNR_RETRY_EXC_ERR => WcStatus::RnrRetryExcErr,
+            ibv_wc_status::IBV_WC_LOC_RDD_VIOL_ERR => WcStatus::LocRddViolErr,
+            ibv_wc_status::IBV_WC_REM_INV_RD_REQ_ERR => WcStatus::RemInvRdReqErr,
+            ibv_wc_status::IBV_WC_REM_ABORT_ERR => WcStatus::RemAbortErr,
+            ibv_wc_status::IBV_WC_INV_EECN_ERR => WcStatus::InvEecnErr,
+            ibv_wc_status::IBV_WC_INV_EEC_STATE_ERR => WcStatus::InvEecStateErr,
+            ibv_wc_status::IBV_WC_FATAL_ERR => WcStatus::FatalErr,
+            ibv_wc_status::IBV_WC_RESP_TIMEOUT_ERR => WcStatus::RespTimeoutErr,
+            ibv_wc_status::IBV_WC_GENERAL_ERR => WcStatus::GeneralErr,
+            ibv_wc_status::IBV_WC_TM_ERR => WcStatus::TmErr,
+            ibv_wc_status::IBV_WC_TM_RNDV_INCOMPLETE => WcStatus::TmRndvIncomplete,
+            status => WcStatus::Unknown(status),
+        }
+    }
+}
+
+impl fmt::Display for WcStatus{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The operation a work completion belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WcOpcode{
+    Send,
+    RdmaWrite,
+    RdmaRead,
+    CompSwap,
+    FetchAdd,
+    BindMw,
+    LocalInv,
+    Tso,
+    Recv,
+    RecvRdmaWithImm,
+    Other(u32),
+}
+
+impl From<ibv_wc_opcode::Type> for WcOpcode{
+    fn from(opcode: ibv_wc_opcode::Type) -> Self{
+        match opcode{
+            ibv_wc_opcode::IBV_WC_SEND => WcOpcode::Send,
+            ibv_wc_opcode::IBV_WC_RDMA_WRITE => WcOpcode::RdmaWrite,
+            ibv_wc_opcode::IBV_WC_RDMA_READ => WcOpcode::RdmaRead,
+            ibv_wc_opcode::IBV_WC_COMP_SWAP => WcOpcode::CompSwap,
+            ibv_wc_opcode::IBV_WC_FETCH_ADD => WcOpcode::FetchAdd,
+            ibv_wc_opcode::IBV_WC_BIND_MW => WcOpcode::BindMw,
+            ibv_wc_opcode::IBV_WC_LOCAL_INV => WcOpcode::LocalInv,
+            ibv_wc_opcode::IBV_WC_TSO => WcOpcode::Tso,
+            ibv_wc_opcode::IBV_WC_RECV => WcOpcode::Recv,
+            ibv_wc_opcode::IBV_WC_RECV_RDMA_WITH_IMM => WcOpcode::RecvRdmaWithImm,
+            opcode => WcOpcode::Other(opcode),
+        }
+    }
+}
+
+impl WcOpcode{
+    /// Whether the completion is for a receive WR rather than a send WR.
+    pub fn is_recv(&self) -> bool{
+        matches!(self, WcOpcode::Recv | WcOpcode::RecvRdmaWithImm)
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct WcFlags: u32{
+        const GRH = ibv_wc_flags::IBV_WC_GRH.0;
+        const WITH_IMM = ibv_wc_flags::IBV_WC_WITH_IMM.0;
+        const IP_CSUM_OK = ibv_wc_flags::IBV_WC_IP_CSUM_OK.0;
+        const WITH_INV = ibv_wc_flags::IBV_WC_WITH_INV.0;
+        const TM_SYNC_REQ = ibv_wc_flags::IBV_WC_TM_SYNC_REQ.0;
+        const TM_MATCH = ibv_wc_flags::IBV_WC_TM_MATCH.0;
+        const TM_DATA_VALID = ibv_wc_flags::IBV_WC_TM_DATA_VALID.0;
+    }
+}
+
+/// A work completion as filled in by `IbvCq::poll`.
+///
+/// This has the layout of `ibv_wc`, so a slice of them is handed to
+/// `ibv_poll_cq` directly without copying.
+#[repr(transparent)]
+pub struct WorkCompletion{
+    inner: ibv_wc,
+}
+
+impl Default for WorkCompletion{
+    fn default() -> Self{
+        WorkCompletion{
+            inner: unsafe{ std::mem::zeroed::<ibv_wc>() },
+        }
+    }
+}
+
+impl Clone for WorkCompletion{
+    fn clone(&self) -> Self{
+        WorkCompletion{
+            inner: unsafe{ std::ptr::read(&self.inner) },
+        }
+    }
+}
+
+impl WorkCompletion{
+    #[cfg(test)]
+    pub(crate) fn with_wr_id(wr_id: u64) -> Self{
+        let mut wc = WorkCompletion::default();
+        wc.inner.wr_id = wr_id;
+        wc
+    }
+    pub fn wr_id(&self) -> u64{
+        self.inner.wr_id
+    }
+    pub fn status(&self) -> WcStatus{
+        self.inner.status.into()
+    }
+    pub fn is_success(&self) -> bool{
+        self.inner.status == ibv_wc_status::IBV_WC_SUCCESS
+    }
+    /// Only valid for successful completions.
+    pub fn opcode(&self) -> WcOpcode{
+        self.inner.opcode.into()
+    }
+    pub fn vendor_err(&self) -> u32{
+        self.inner.vendor_err
+    }
+    /// Number of bytes transferred, for receives and RDMA reads.
+    pub fn byte_len(&self) -> u32{
+        self.inner.byte_len
+    }
+    /// The immediate data in host byte order, if the completion carries any.
+    pub fn imm_data(&self) -> Option<u32>{
+        if self.flags().contains(WcFlags::WITH_IMM) {
+            Some(u32::from_be(unsafe{ self.inner.imm_data_invalidated_rkey_union.imm_data }))
+        } else {
+            None
+        }
+    }
+    /// The rkey invalidated by a send with invalidate, if any.
+    pub fn invalidated_rkey(&self) -> Option<u32>{
+        if self.flags().contains(WcFlags::WITH_INV) {
+            Some(unsafe{ self.inner.imm_data_invalidated_rkey_union.invalidated_rkey })
+        } else {
+            None
+        }
+    }
+    pub fn qp_num(&self) -> u32{
+        self.inner.qp_num
+    }
+    pub fn src_qp(&self) -> u32{
+        self.inner.src_qp
+    }
+    pub fn flags(&self) -> WcFlags{
+        WcFlags::from_bits_retain(self.inner.wc_flags)
+    }
+    pub fn pkey_index(&self) -> u16{
+        self.inner.pkey_index
+    }
+    pub fn slid(&self) -> u16{
+        self.inner.slid
+    }
+    pub fn sl(&self) -> u8{
+        self.inner.sl
+    }
+    /// Turns a failed completion into an error.
+    pub fn result(&self) -> Result<(), IbvError>{
+        if self.is_success() {
+            return Ok(());
+        }
+        Err(IbvError::WorkCompletion{
+            status: self.status(),
+            vendor_err: self.vendor_err(),
+            wr_id: self.wr_id(),
+            qp_num: self.qp_num(),
+        })
+    }
+}
+
+impl fmt::Debug for WorkCompletion{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        let mut s = f.debug_struct("WorkCompletion");
+        s.field("wr_id", &self.wr_id())
+            .field("status", &self.status());
+        if self.is_success() {
+            s.field("opcode", &self.opcode())
+                .field("byte_len", &self.byte_len())
+                .field("imm_data", &self.imm_data());
+        } else {
+            s.field("vendor_err", &self.vendor_err());
+        }
+        s.field("qp_num", &self.qp_num())
+            .field("src_qp", &self.src_qp())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
@@ -0,0 +1,690 @@
+use std::{ffi::CStr, fmt, fs, path::PathBuf};
+use log::warn;
+use rdma_sys::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{IbvContext, IbvError, IbvQpType, Result};
+
+/// The list returned by `ibv_get_device_list`, freed again on drop.
+///
+/// Device pointers are only valid while the list is alive, unless the
+/// device has been opened, in which case the context keeps it alive.
+pub struct IbvDeviceList{
+    inner: *mut *mut ibv_device,
+    len: usize,
+}
+
+impl IbvDeviceList{
+    pub fn new() -> Result<Self>{
+        let mut num_devices = 0;
+        let list = unsafe{ ibv_get_device_list(&mut num_devices) };
+        if list.is_null() {
+            return Err(IbvError::last_os_error("ibv_get_device_list"));
+        }
+        Ok(IbvDeviceList{
+            inner: list,
+            len: num_devices.max(0) as usize,
+        })
+    }
+    pub fn len(&self) -> usize{
+        self.len
+    }
+    pub fn is_empty(&self) -> bool{
+        self.len == 0
+    }
+    pub fn get(&self, index: usize) -> Option<*mut ibv_device>{
+        if index >= self.len {
+            return None;
+        }
+        let device = unsafe{ *self.inner.add(index) };
+        if device.is_null() {
+            None
+        } else {
+            Some(device)
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = *mut ibv_device> + '_{
+        (0..self.len).filter_map(move |i| self.get(i))
+    }
+    /// Opens the device at `index`. The returned context stays valid after
+    /// the list is dropped.
+    pub fn open(&self, index: usize) -> Result<IbvContext>{
+        let device = self.get(index).ok_or_else(|| IbvError::DeviceNotFound(format!("index {}", index)))?;
+        open_device(device)
+    }
+    /// Collects name, GUID, node type, transport, firmware version and port
+    /// count of every device. Each device is opened only for the duration
+    /// of its `ibv_query_device` call. Devices that can't be opened or
+    /// queried are logged and left out.
+    pub fn info(&self) -> Result<Vec<IbvDeviceInfo>>{
+        let mut infos = Vec::new();
+        for device in self.iter(){
+            match device_info(device){
+                Ok(info) => infos.push(info),
+                Err(err) => warn!("Skipping device {}: {}", raw_device_name(device), err),
+            }
+        }
+        Ok(infos)
+    }
+}
+
+impl Drop for IbvDeviceList{
+    fn drop(&mut self){
+        unsafe{ ibv_free_device_list(self.inner) };
+    }
+}
+
+/// Returns every RDMA device on the host along with its basic attributes.
+pub fn list_devices() -> Result<Vec<IbvDeviceInfo>>{
+    IbvDeviceList::new()?.info()
+}
+
+#[derive(Debug, Clone)]
+pub struct IbvDeviceInfo{
+    pub name: String,
+    /// Node GUID in host byte order.
+    pub node_guid: u64,
+    pub node_type: NodeType,
+    pub transport: TransportType,
+    pub fw_ver: String,
+    pub phys_port_cnt: u8,
+}
+
+impl IbvDeviceInfo{
+    /// Formats the node GUID the way `ibv_devices` prints it.
+    pub fn node_guid_string(&self) -> String{
+        let guid = self.node_guid;
+        format!("{:04x}:{:04x}:{:04x}:{:04x}", guid >> 48, (guid >> 32) & 0xffff, (guid >> 16) & 0xffff, guid & 0xffff)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeType{
+    Ca,
+    Switch,
+    Router,
+    Rnic,
+    Usnic,
+    UsnicUdp,
+    Unspecified,
+    Unknown,
+}
+
+impl From<ibv_node_type::Type> for NodeType{
+    fn from(node_type: ibv_node_type::Type) -> Self{
+        match node_type{
+            ibv_node_type::IBV_NODE_CA => NodeType::Ca,
+            ibv_node_type::IBV_NODE_SWITCH => NodeType::Switch,
+            ibv_node_type::IBV_NODE_ROUTER => NodeType::Router,
+            ibv_node_type::IBV_NODE_RNIC => NodeType::Rnic,
+            ibv_node_type::IBV_NODE_USNIC => NodeType::Usnic,
+            ibv_node_type::IBV_NODE_USNIC_UDP => NodeType::UsnicUdp,
+            ibv_node_type::IBV_NODE_UNSPECIFIED => NodeType::Unspecified,
+            _ => NodeType::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for NodeType{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        let s = match self{
+            NodeType::Ca => "InfiniBand channel adapter",
+            NodeType::Switch => "InfiniBand switch",
+            NodeType::Router => "InfiniBand router",
+            NodeType::Rnic => "iWARP NIC",
+            NodeType::Usnic => "usNIC",
+            NodeType::UsnicUdp => "usNIC UDP",
+            NodeType::Unspecified => "unspecified",
+            NodeType::Unknown => "unknown",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportType{
+    Ib,
+    Iwarp,
+    Usnic,
+    UsnicUdp,
+    Unspecified,
+    Unknown,
+}
+
+impl From<ibv_transport_type::Type> for TransportType{
+    fn from(transport: ibv_transport_type::Type) -> Self{
+        match transport{
+            ibv_transport_type::IBV_TRANSPORT_IB => TransportType::Ib,
+            ibv_transport_type::IBV_TRANSPORT_IWARP => TransportType::Iwarp,
+            ibv_transport_type::IBV_TRANSPORT_USNIC => TransportType::Usnic,
+            ibv_transport_type::IBV_TRANSPORT_USNIC_UDP => TransportType::UsnicUdp,
+            ibv_transport_type::IBV_TRANSPORT_UNSPECIFIED => TransportType::Unspecified,
+            _ => TransportType::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for TransportType{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        let s = match self{
+            TransportType::Ib => "InfiniBand",
+            TransportType::Iwarp => "iWARP",
+            TransportType::Usnic => "usNIC",
+            TransportType::UsnicUdp => "usNIC UDP",
+            TransportType::Unspecified => "unspecified",
+            TransportType::Unknown => "unknown",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+pub(crate) fn open_device(device: *mut ibv_device) -> Result<IbvContext>{
+    let context = unsafe{ ibv_open_device(device) };
+    if context.is_null() {
+        return Err(IbvError::last_os_error("ibv_open_device").with_device(raw_device_name(device)));
+    }
+    Ok(IbvContext::from_context(context))
+}
+
+pub(crate) fn raw_device_name(device: *mut ibv_device) -> String{
+    unsafe{ CStr::from_ptr((*device).name.as_ptr()) }.to_string_lossy().into_owned()
+}
+
+/// The NUMA node the device is attached to, `None` if the platform does not
+/// report one.
+pub(crate) fn numa_node(device_name: &str) -> Result<Option<u32>>{
+    let path = PathBuf::from(format!("/sys/class/infiniband/{}/device/numa_node", device_name));
+    let node = fs::read_to_string(&path).map_err(|source| IbvError::Sysfs{ path, source })?;
+    // -1 means the device is not associated with a node.
+    Ok(node.trim().parse::<u32>().ok())
+}
+
+fn device_info(device: *mut ibv_device) -> Result<IbvDeviceInfo>{
+    let name = raw_device_name(device);
+    let context = open_device(device)?;
+    let mut device_attr = unsafe{ std::mem::zeroed::<ibv_device_attr>() };
+    let ret = unsafe{ ibv_query_device(context.as_ptr(), &mut device_attr) };
+    if ret != 0 {
+        return Err(IbvError::from_ret("ibv_query_device", ret).with_device(name));
+    }
+    let fw_ver = unsafe{ CStr::from_ptr(device_attr.fw_ver.as_ptr()) }.to_string_lossy().into_owned();
+    let (node_type, transport) = unsafe{ ((*device).node_type, (*device).transport_type) };
+    Ok(IbvDeviceInfo{
+        name,
+        node_guid: u64::from_be(unsafe{ ibv_get_device_guid(device) }),
+        node_type: node_type.into(),
+        transport: transport.into(),
+        fw_ver,
+        phys_port_cnt: device_attr.phys_port_cnt,
+    })
+}
+
+/// Device capabilities as reported by `ibv_query_device_ex`, falling back
+/// to `ibv_query_device` on providers without the extended verb. Extended
+/// fields are zero in that case.
+#[derive(Debug, Clone)]
+pub struct IbvDeviceAttr{
+    pub fw_ver: String,
+    /// Node GUID in host byte order.
+    pub node_guid: u64,
+    /// System image GUID in host byte order.
+    pub sys_image_guid: u64,
+    pub max_mr_size: u64,
+    pub page_size_cap: u64,
+    pub vendor_id: u32,
+    pub vendor_part_id: u32,
+    pub hw_ver: u32,
+    pub max_qp: u32,
+    pub max_qp_wr: u32,
+    pub device_cap_flags: u32,
+    pub max_sge: u32,
+    pub max_sge_rd: u32,
+    pub max_cq: u32,
+    pub max_cqe: u32,
+    pub max_mr: u32,
+    pub max_pd: u32,
+    /// Outstanding RDMA reads/atomics a QP can serve as the target.
+    pub max_qp_rd_atom: u32,
+    pub max_res_rd_atom: u32,
+    /// Outstanding RDMA reads/atomics a QP can initiate.
+    pub max_qp_init_rd_atom: u32,
+    pub atomic_cap: AtomicCap,
+    pub max_mw: u32,
+    pub max_srq: u32,
+    pub max_srq_wr: u32,
+    pub max_srq_sge: u32,
+    pub max_pkeys: u16,
+    pub local_ca_ack_delay: u8,
+    pub phys_port_cnt: u8,
+    pub odp_caps: OdpCaps,
+    pub completion_timestamp_mask: u64,
+    /// Core clock of the HCA in kHz, used to convert completion timestamps.
+    pub hca_core_clock: u64,
+    pub device_cap_flags_ex: u64,
+    pub max_tso: u32,
+    pub max_wq_type_rq: u32,
+    pub raw_packet_caps: u32,
+    pub max_dm_size: u64,
+}
+
+impl IbvDeviceAttr{
+    pub(crate) fn query(context: *mut ibv_context) -> Result<Self>{
+        let mut attr = unsafe{ std::mem::zeroed::<ibv_device_attr_ex>() };
+        let ret = unsafe{ ibv_query_device_ex(context, std::ptr::null(), &mut attr) };
+        if ret != 0 {
+            return Err(IbvError::from_ret("ibv_query_device_ex", ret).with_device(crate::device_name(context)));
+        }
+        Ok(IbvDeviceAttr::from_raw(&attr))
+    }
+    pub(crate) fn from_raw(attr: &ibv_device_attr_ex) -> Self{
+        let orig = &attr.orig_attr;
+        IbvDeviceAttr{
+            fw_ver: unsafe{ CStr::from_ptr(orig.fw_ver.as_ptr()) }.to_string_lossy().into_owned(),
+            node_guid: u64::from_be(orig.node_guid),
+            sys_image_guid: u64::from_be(orig.sys_image_guid),
+            max_mr_size: orig.max_mr_size,
+            page_size_cap: orig.page_size_cap,
+            vendor_id: orig.vendor_id,
+            vendor_part_id: orig.vendor_part_id,
+            hw_ver: orig.hw_ver,
+            max_qp: orig.max_qp.max(0) as u32,
+            max_qp_wr: orig.max_qp_wr.max(0) as u32,
+            device_cap_flags: orig.device_cap_flags,
+            max_sge: orig.max_sge.max(0) as u32,
+            max_sge_rd: orig.max_sge_rd.max(0) as u32,
+            max_cq: orig.max_cq.max(0) as u32,
+            max_cqe: orig.max_cqe.max(0) as u32,
+            max_mr: orig.max_mr.max(0) as u32,
+            max_pd: orig.max_pd.max(0) as u32,
+            max_qp_rd_atom: orig.max_qp_rd_atom.max(0) as u32,
+            max_res_rd_atom: orig.max_res_rd_atom.max(0) as u32,
+            max_qp_init_rd_atom: orig.max_qp_init_rd_atom.max(0) as u32,
+            atomic_cap: orig.atomic_cap.into(),
+            max_mw: orig.max_mw.max(0) as u32,
+            max_srq: orig.max_srq.max(0) as u32,
+            max_srq_wr: orig.max_srq_wr.max(0) as u32,
+            max_srq_sge: orig.max_srq_sge.max(0) as u32,
+            max_pkeys: orig.max_pkeys,
+            local_ca_ack_delay: orig.local_ca_ack_delay,
+            phys_port_cnt: orig.phys_port_cnt,
+            odp_caps: OdpCaps{
+                general_caps: attr.odp_caps.general_caps,
+                rc_odp_caps: attr.odp_caps.per_transport_caps.rc_odp_caps,
+                uc_odp_caps: attr.odp_caps.per_transport_caps.uc_odp_caps,
+                ud_odp_caps: attr.odp_caps.per_transport_caps.ud_odp_caps,
+                xrc_odp_caps: attr.xrc_odp_caps,
+            },
+            completion_timestamp_mask: attr.completion_timestamp_mask,
+            hca_core_clock: attr.hca_core_clock,
+            device_cap_flags_ex: attr.device_cap_flags_ex,
+            max_tso: attr.tso_caps.max_tso,
+            max_wq_type_rq: attr.max_wq_type_rq,
+            raw_packet_caps: attr.raw_packet_caps,
+            max_dm_size: attr.max_dm_size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtomicCap{
+    /// Atomic operations are not supported.
+    None,
+    /// Atomicity is guaranteed between QPs on this device only.
+    Hca,
+    /// Atomicity is guaranteed against any other agent, including the CPU.
+    Glob,
+}
+
+impl AtomicCap{
+    pub fn is_supported(&self) -> bool{
+        *self != AtomicCap::None
+    }
+}
+
+impl From<ibv_atomic_cap::Type> for AtomicCap{
+    fn from(cap: ibv_atomic_cap::Type) -> Self{
+        match cap{
+            ibv_atomic_cap::IBV_ATOMIC_HCA => AtomicCap::Hca,
+            ibv_atomic_cap::IBV_ATOMIC_GLOB => AtomicCap::Glob,
+            _ => AtomicCap::None,
+        }
+    }
+}
+
+/// On-demand paging capabilities. The per-transport fields are bitmasks of
+/// `ibv_odp_transport_cap_bits`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OdpCaps{
+    pub general_caps: u64,
+    pub rc_odp_caps: u32,
+    pub uc_odp_caps: u32,
+    pub ud_odp_caps: u32,
+    pub xrc_odp_caps: u32,
+}
+
+impl OdpCaps{
+    pub fn is_supported(&self) -> bool{
+        self.general_caps & ibv_odp_general_caps::IBV_ODP_SUPPORT.0 as u64 != 0
+    }
+    pub fn supports_implicit(&self) -> bool{
+        self.general_caps & ibv_odp_general_caps::IBV_ODP_SUPPORT_IMPLICIT.0 as u64 != 0
+    }
+    /// The `ibv_odp_transport_cap_bits` for QPs of `qp_type`.
+    pub fn transport_caps(&self, qp_type: IbvQpType) -> u32{
+        match qp_type{
+            IbvQpType::Rc => self.rc_odp_caps,
+            IbvQpType::Uc => self.uc_odp_caps,
+            IbvQpType::Ud => self.ud_odp_caps,
+            IbvQpType::XrcSend | IbvQpType::XrcRecv => self.xrc_odp_caps,
+            IbvQpType::RawPacket | IbvQpType::Driver => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IbvPortAttr{
+    pub port: u8,
+    pub state: PortState,
+    /// `None` if the device reports an MTU outside the verbs range.
+    pub max_mtu: Option<Mtu>,
+    pub active_mtu: Option<Mtu>,
+    pub link_layer: LinkLayer,
+    pub lid: u16,
+    pub sm_lid: u16,
+    pub sm_sl: u8,
+    pub lmc: u8,
+    pub active_width: PortWidth,
+    pub active_speed: PortSpeed,
+    /// Raw physical port state (5 = LinkUp, 3 = Disabled, ...).
+    pub phys_state: u8,
+    pub gid_tbl_len: i32,
+    pub pkey_tbl_len: u16,
+    pub max_msg_sz: u32,
+    pub port_cap_flags: u32,
+}
+
+impl IbvPortAttr{
+    pub(crate) fn query(context: *mut ibv_context, port: u8) -> Result<Self>{
+        let mut attr = unsafe{ std::mem::zeroed::<ibv_port_attr>() };
+        let ret = unsafe{ ___ibv_query_port(context, port, &mut attr) };
+        if ret != 0 {
+            return Err(IbvError::from_ret("ibv_query_port", ret).with_device(crate::device_name(context)).with_port(port));
+        }
+        Ok(IbvPortAttr::from_raw(port, &attr))
+    }
+    pub(crate) fn from_raw(port: u8, attr: &ibv_port_attr) -> Self{
+        IbvPortAttr{
+            port,
+            state: attr.state.into(),
+            max_mtu: Mtu::from_ibv(attr.max_mtu),
+            active_mtu: Mtu::from_ibv(attr.active_mtu),
+            link_layer: attr.link_layer.into(),
+            lid: attr.lid,
+            sm_lid: attr.sm_lid,
+            sm_sl: attr.sm_sl,
+            lmc: attr.lmc,
+            active_width: attr.active_width.into(),
+            active_speed: attr.active_speed.into(),
+            phys_state: attr.phys_state,
+            gid_tbl_len: attr.gid_tbl_len,
+            pkey_tbl_len: attr.pkey_tbl_len,
+            max_msg_sz: attr.max_msg_sz,
+            port_cap_flags: attr.port_cap_flags,
+        }
+    }
+    pub fn is_active(&self) -> bool{
+        self.state == PortState::Active
+    }
+    /// Nominal link rate in Gb/s (lanes times per-lane signalling rate).
+    pub fn link_gbps(&self) -> f64{
+        self.active_width.lanes() as f64 * self.active_speed.gbps_per_lane()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState{
+    Nop,
+    Down,
+    Init,
+    Armed,
+    Active,
+    ActiveDefer,
+    Unknown,
+}
+
+impl From<ibv_port_state::Type> for PortState{
+    fn from(state: ibv_port_state::Type) -> Self{
+        match state{
+            ibv_port_state::IBV_PORT_NOP => PortState::Nop,
+            ibv_port_state::IBV_PORT_DOWN => PortState::Down,
+            ibv_port_state::IBV_PORT_INIT => PortState::Init,
+            ibv_port_state::IBV_PORT_ARMED => PortState::Armed,
+            ibv_port_state::IBV_PORT_ACTIVE => PortState::Active,
+            ibv_port_state::IBV_PORT_ACTIVE_DEFER => PortState::ActiveDefer,
+            _ => PortState::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for PortState{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        let s = match self{
+            PortState::Nop => "NOP",
+            PortState::Down => "DOWN",
+            PortState::Init => "INIT",
+            PortState::Armed => "ARMED",
+            PortState::Active => "ACTIVE",
+            PortState::ActiveDefer => "ACTIVE_DEFER",
+            PortState::Unknown => "UNKNOWN",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Path MTU as understood by the verbs API.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Mtu{
+    Mtu256,
+    Mtu512,
+    #[default]
+    Mtu1024,
+    Mtu2048,
+    Mtu4096,
+}
+
+impl Mtu{
+    pub fn from_ibv(mtu: ibv_mtu::Type) -> Option<Self>{
+        match mtu{
+            ibv_mtu::IBV_MTU_256 => Some(Mtu::Mtu256),
+            ibv_mtu::IBV_MTU_512 => Some(Mtu::Mtu512),
+            ibv_mtu::IBV_MTU_1024 => Some(Mtu::Mtu1024),
+            ibv_mtu::IBV_MTU_2048 => Some(Mtu::Mtu2048),
+            ibv_mtu::IBV_MTU_4096 => Some(Mtu::Mtu4096),
+            _ => None,
+        }
+    }
+    pub fn get(&self) -> ibv_mtu::Type{
+        match self{
+            Mtu::Mtu256 => ibv_mtu::IBV_MTU_256,
+            Mtu::Mtu512 => ibv_mtu::IBV_MTU_512,
+            Mtu::Mtu1024 => ibv_mtu::IBV_MTU_1024,
+            Mtu::Mtu2048 => ibv_mtu::IBV_MTU_2048,
+            Mtu::Mtu4096 => ibv_mtu::IBV_MTU_4096,
+        }
+    }
+    pub fn bytes(&self) -> u32{
+        match self{
+            Mtu::Mtu256 => 256,
+            Mtu::Mtu512 => 512,
+            Mtu::Mtu1024 => 1024,
+            Mtu::Mtu2048 => 2048,
+            Mtu::Mtu4096 => 4096,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkLayer{
+    Unspecified,
+    InfiniBand,
+    Ethernet,
+}
+
+impl From<u8> for LinkLayer{
+    fn from(link_layer: u8) -> Self{
+        // IBV_LINK_LAYER_* from <infiniband/verbs.h>
+        match link_layer{
+            1 => LinkLayer::InfiniBand,
+            2 => LinkLayer::Ethernet,
+            _ => LinkLayer::Unspecified,
+        }
+    }
+}
+
+impl fmt::Display for LinkLayer{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        let s = match self{
+            LinkLayer::Unspecified => "unspecified",
+            LinkLayer::InfiniBand => "InfiniBand",
+            LinkLayer::Ethernet => "Ethernet",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortWidth{
+    X1,
+    X2,
+    X4,
+    X8,
+    X12,
+    Unknown(u8),
+}
+
+impl PortWidth{
+    pub fn lanes(&self) -> u32{
+        match self{
+            PortWidth::X1 => 1,
+            PortWidth::X2 => 2,
+            PortWidth::X4 => 4,
+            PortWidth::X8 => 8,
+            PortWidth::X12 => 12,
+            PortWidth::Unknown(_) => 0,
+        }
+    }
+}
+
+impl From<u8> for PortWidth{
+    fn from(width: u8) -> Self{
+        match width{
+            1 => PortWidth::X1,
+            2 => PortWidth::X4,
+            4 => PortWidth::X8,
+            8 => PortWidth::X12,
+            16 => PortWidth::X2,
+            other => PortWidth::Unknown(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortSpeed{
+    Sdr,
+    Ddr,
+    Qdr,
+    Fdr10,
+    Fdr,
+    Edr,
+    Hdr,
+    Ndr,
+    Unknown(u8),
+}
+
+impl PortSpeed{
+    pub fn gbps_per_lane(&self) -> f64{
+        match self{
+            PortSpeed::Sdr => 2.5,
+            PortSpeed::Ddr => 5.0,
+            PortSpeed::Qdr => 10.0,
+            PortSpeed::Fdr10 => 10.3125,
+            PortSpeed::Fdr => 14.0625,
+            PortSpeed::Edr => 25.78125,
+            PortSpeed::Hdr => 50.0,
+            PortSpeed::Ndr => 100.0,
+            PortSpeed::Unknown(_) => 0.0,
+        }
+    }
+}
+
+impl From<u8> for PortSpeed{
+    fn from(speed: u8) -> Self{
+        match speed{
+            1 => PortSpeed::Sdr,
+            2 => PortSpeed::Ddr,
+            4 => PortSpeed::Qdr,
+            8 => PortSpeed::Fdr10,
+            16 => PortSpeed::Fdr,
+            32 => PortSpeed::Edr,
+            64 => PortSpeed::Hdr,
+            128 => PortSpeed::Ndr,
+            other => PortSpeed::Unknown(other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn port_width_decodes_ib_encoding(){
+        let widths = [(1, PortWidth::X1), (2, PortWidth::X4), (4, PortWidth::X8), (8, PortWidth::X12), (16, PortWidth::X2)];
+        for (raw, width) in widths{
+            assert_eq!(PortWidth::from(raw), width);
+        }
+        assert_eq!(PortWidth::from(3), PortWidth::Unknown(3));
+        assert_eq!(PortWidth::Unknown(3).lanes(), 0);
+    }
+
+    #[test]
+    fn port_speed_decodes_ib_encoding(){
+        let speeds = [
+            (1, PortSpeed::Sdr), (2, PortSpeed::Ddr), (4, PortSpeed::Qdr), (8, PortSpeed::Fdr10),
+            (16, PortSpeed::Fdr), (32, PortSpeed::Edr), (64, PortSpeed::Hdr), (128, PortSpeed::Ndr),
+        ];
+        for (raw, speed) in speeds{
+            assert_eq!(PortSpeed::from(raw), speed);
+        }
+        assert_eq!(PortSpeed::from(0), PortSpeed::Unknown(0));
+        assert_eq!(PortSpeed::Unknown(0).gbps_per_lane(), 0.0);
+    }
+
+    #[test]
+    fn link_rate_is_lanes_times_lane_rate(){
+        let mut attr = unsafe{ std::mem::zeroed::<ibv_port_attr>() };
+        attr.active_width = 2;
+        attr.active_speed = 32;
+        assert_eq!(IbvPortAttr::from_raw(1, &attr).link_gbps(), 4.0 * 25.78125);
+    }
+
+    #[test]
+    fn mtu_round_trips_and_rejects_unknown(){
+        for mtu in [Mtu::Mtu256, Mtu::Mtu512, Mtu::Mtu1024, Mtu::Mtu2048, Mtu::Mtu4096]{
+            assert_eq!(Mtu::from_ibv(mtu.get()), Some(mtu));
+        }
+        assert_eq!(Mtu::Mtu4096.bytes(), 4096);
+        assert_eq!(Mtu::from_ibv(0), None);
+        assert_eq!(Mtu::from_ibv(6), None);
+    }
+
+    #[test]
+    fn port_attr_keeps_unknown_mtu_as_none(){
+        let mut attr = unsafe{ std::mem::zeroed::<ibv_port_attr>() };
+        attr.max_mtu = ibv_mtu::IBV_MTU_4096;
+        attr.active_mtu = 7;
+        let port_attr = IbvPortAttr::from_raw(1, &attr);
+        assert_eq!(port_attr.max_mtu, Some(Mtu::Mtu4096));
+        assert_eq!(port_attr.active_mtu, None);
+    }
+}
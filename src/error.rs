@@ -0,0 +1,270 @@
+use std::{fmt, io, path::PathBuf};
+
+use crate::{completion::WcStatus, device::PortState, qp::IbvQpState, IbvQpType};
+
+pub type Result<T> = std::result::Result<T, IbvError>;
+
+/// Coarse classification of a failure, derived from the errno reported by
+/// the verb, so callers can react without matching on raw numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IbvErrorKind{
+    /// The device was removed or hit a fatal error (ENODEV, EIO, ENXIO).
+    DeviceGone,
+    /// The driver or kernel ran out of memory or resources (ENOMEM, ENOSPC).
+    OutOfMemory,
+    /// An attribute or argument was rejected (EINVAL, ERANGE).
+    InvalidAttribute,
+    /// The caller is not allowed to perform the operation (EPERM, EACCES).
+    PermissionDenied,
+    /// The device or provider does not implement the operation.
+    NotSupported,
+    /// The resource is busy or the operation would block (EBUSY, EAGAIN).
+    Busy,
+    /// The operation did not finish in time (ETIMEDOUT).
+    Timeout,
+    /// The port is not in the ACTIVE state, e.g. because the cable is unplugged.
+    PortDown,
+    /// The requested device, port or entry does not exist.
+    NotFound,
+    Other,
+}
+
+impl IbvErrorKind{
+    pub fn from_errno(errno: i32) -> Self{
+        match errno{
+            libc::ENODEV | libc::EIO | libc::ENXIO => IbvErrorKind::DeviceGone,
+            libc::ENOMEM | libc::ENOSPC => IbvErrorKind::OutOfMemory,
+            libc::EINVAL | libc::ERANGE => IbvErrorKind::InvalidAttribute,
+            libc::EPERM | libc::EACCES => IbvErrorKind::PermissionDenied,
+            libc::EOPNOTSUPP | libc::ENOSYS | libc::EPROTONOSUPPORT => IbvErrorKind::NotSupported,
+            libc::EBUSY | libc::EAGAIN => IbvErrorKind::Busy,
+            libc::ETIMEDOUT => IbvErrorKind::Timeout,
+            libc::ENOENT => IbvErrorKind::NotFound,
+            _ => IbvErrorKind::Other,
+        }
+    }
+}
+
+/// Identifies the object a failing verb was operating on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorContext{
+    pub device: Option<String>,
+    pub port: Option<u8>,
+    pub qp_num: Option<u32>,
+    pub wr_id: Option<u64>,
+}
+
+impl ErrorContext{
+    fn is_empty(&self) -> bool{
+        self.device.is_none() && self.port.is_none() && self.qp_num.is_none() && self.wr_id.is_none()
+    }
+}
+
+impl fmt::Display for ErrorContext{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        let mut parts = Vec::new();
+        if let Some(device) = &self.device{
+            parts.push(format!("device {}", device));
+        }
+        if let Some(port) = self.port{
+            parts.push(format!("port {}", port));
+        }
+        if let Some(qp_num) = self.qp_num{
+            parts.push(format!("qp {}", qp_num));
+        }
+        if let Some(wr_id) = self.wr_id{
+            parts.push(format!("wr_id {}", wr_id));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+#[derive(Debug)]
+pub enum IbvError{
+    /// A verb returned a non-zero code or a NULL object.
+    Verb{
+        verb: &'static str,
+        errno: i32,
+        context: ErrorContext,
+    },
+    /// No device matched the lookup.
+    DeviceNotFound(String),
+    /// A sysfs attribute describing the device could not be read.
+    Sysfs{
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// The port is not ACTIVE, so no connection can be established over it.
+    PortNotActive{
+        device: String,
+        port: u8,
+        state: PortState,
+    },
+    /// A work request completed with a non-success status.
+    WorkCompletion{
+        status: WcStatus,
+        vendor_err: u32,
+        wr_id: u64,
+        qp_num: u32,
+    },
+    /// The QP is not in the state the operation requires.
+    QpState{
+        qp_num: u32,
+        expected: &'static str,
+        actual: IbvQpState,
+    },
+    /// The device cannot use on-demand paging for the requested operation.
+    OdpNotSupported{
+        device: String,
+        qp_type: IbvQpType,
+        operation: &'static str,
+    },
+    /// The device or QP is not set up for the requested operation.
+    OperationNotSupported{
+        device: String,
+        operation: &'static str,
+        reason: String,
+    },
+    /// An `MrPool` with the `Fail` policy has no free buffer of the size.
+    PoolExhausted{
+        size: usize,
+    },
+    /// An argument was rejected by the library before reaching a verb.
+    InvalidArgument{
+        function: &'static str,
+        reason: String,
+    },
+}
+
+impl IbvError{
+    pub fn verb(verb: &'static str, errno: i32) -> Self{
+        IbvError::Verb{
+            verb,
+            errno,
+            context: ErrorContext::default(),
+        }
+    }
+    /// Builds an error for a verb that returned NULL and reported the cause
+    /// through `errno`.
+    pub fn last_os_error(verb: &'static str) -> Self{
+        let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+        IbvError::verb(verb, errno)
+    }
+    /// Builds an error from a verb's return code. Verbs either return the
+    /// errno directly, a negated errno, or -1 with `errno` set.
+    pub fn from_ret(verb: &'static str, ret: i32) -> Self{
+        let errno = match ret{
+            ret if ret > 0 => ret,
+            -1 => io::Error::last_os_error().raw_os_error().unwrap_or(1),
+            ret => -ret,
+        };
+        IbvError::verb(verb, errno)
+    }
+    pub fn invalid_argument(function: &'static str, reason: impl Into<String>) -> Self{
+        IbvError::InvalidArgument{
+            function,
+            reason: reason.into(),
+        }
+    }
+    pub fn with_device(mut self, device: impl Into<String>) -> Self{
+        if let IbvError::Verb{ context, .. } = &mut self{
+            context.device = Some(device.into());
+        }
+        self
+    }
+    pub fn with_port(mut self, port: u8) -> Self{
+        if let IbvError::Verb{ context, .. } = &mut self{
+            context.port = Some(port);
+        }
+        self
+    }
+    pub fn with_qp(mut self, qp_num: u32) -> Self{
+        if let IbvError::Verb{ context, .. } = &mut self{
+            context.qp_num = Some(qp_num);
+        }
+        self
+    }
+    pub fn with_wr_id(mut self, wr_id: u64) -> Self{
+        if let IbvError::Verb{ context, .. } = &mut self{
+            context.wr_id = Some(wr_id);
+        }
+        self
+    }
+    pub fn kind(&self) -> IbvErrorKind{
+        match self{
+            IbvError::Verb{ errno, .. } => IbvErrorKind::from_errno(*errno),
+            IbvError::DeviceNotFound(_) => IbvErrorKind::NotFound,
+            IbvError::PortNotActive{ .. } => IbvErrorKind::PortDown,
+            IbvError::Sysfs{ source, .. } => match source.raw_os_error(){
+                Some(errno) => IbvErrorKind::from_errno(errno),
+                None => IbvErrorKind::Other,
+            },
+            IbvError::WorkCompletion{ .. } => IbvErrorKind::Other,
+            IbvError::QpState{ .. } => IbvErrorKind::InvalidAttribute,
+            IbvError::OdpNotSupported{ .. } => IbvErrorKind::NotSupported,
+            IbvError::OperationNotSupported{ .. } => IbvErrorKind::NotSupported,
+            IbvError::PoolExhausted{ .. } => IbvErrorKind::OutOfMemory,
+            IbvError::InvalidArgument{ .. } => IbvErrorKind::InvalidAttribute,
+        }
+    }
+    pub fn errno(&self) -> Option<i32>{
+        match self{
+            IbvError::Verb{ errno, .. } => Some(*errno),
+            IbvError::Sysfs{ source, .. } => source.raw_os_error(),
+            IbvError::InvalidArgument{ .. } => Some(libc::EINVAL),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for IbvError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            IbvError::Verb{ verb, errno, context } => {
+                write!(f, "{} failed: {}", verb, io::Error::from_raw_os_error(*errno))?;
+                if !context.is_empty(){
+                    write!(f, " ({})", context)?;
+                }
+                Ok(())
+            },
+            IbvError::DeviceNotFound(lookup) => write!(f, "no RDMA device found for {}", lookup),
+            IbvError::Sysfs{ path, source } => write!(f, "failed to read {}: {}", path.display(), source),
+            IbvError::PortNotActive{ device, port, state } => write!(
+                f,
+                "port {} of device {} is {} instead of ACTIVE, check the link",
+                port, device, state
+            ),
+            IbvError::WorkCompletion{ status, vendor_err, wr_id, qp_num } => write!(
+                f,
+                "work request {} on qp {} completed with {} ({}, vendor error {:#x})",
+                wr_id, qp_num, status, status.get(), vendor_err
+            ),
+            IbvError::QpState{ qp_num, expected, actual } => write!(
+                f,
+                "qp {} is in state {} instead of {}",
+                qp_num, actual, expected
+            ),
+            IbvError::OdpNotSupported{ device, qp_type, operation } => write!(
+                f,
+                "device {} does not support on-demand paging for {} on {:?} QPs",
+                device, operation, qp_type
+            ),
+            IbvError::OperationNotSupported{ device, operation, reason } => write!(
+                f,
+                "device {} cannot perform {}: {}",
+                device, operation, reason
+            ),
+            IbvError::PoolExhausted{ size } => write!(f, "no free pool buffer for {} bytes", size),
+            IbvError::InvalidArgument{ function, reason } => write!(f, "invalid argument to {}: {}", function, reason),
+        }
+    }
+}
+
+impl std::error::Error for IbvError{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>{
+        match self{
+            IbvError::Sysfs{ source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
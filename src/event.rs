@@ -0,0 +1,244 @@
+use std::{collections::HashMap, marker::PhantomData, sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, Mutex, OnceLock}, thread::{self, JoinHandle}, time::Duration};
+use log::{info, warn};
+use rdma_sys::*;
+
+use crate::{device_name, IbvContext, IbvError, Result};
+
+/// How often the dispatcher thread checks whether it should stop.
+const DISPATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An asynchronous event reported by the device, decoded from
+/// `ibv_async_event`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsyncEvent{
+    CqError,
+    QpFatal{ qp_num: u32 },
+    QpRequestError{ qp_num: u32 },
+    QpAccessError{ qp_num: u32 },
+    CommEstablished{ qp_num: u32 },
+    SqDrained{ qp_num: u32 },
+    PathMigrated{ qp_num: u32 },
+    PathMigrationError{ qp_num: u32 },
+    QpLastWqeReached{ qp_num: u32 },
+    SrqError,
+    SrqLimitReached,
+    WqFatal,
+    PortActive{ port: u8 },
+    PortError{ port: u8 },
+    LidChange{ port: u8 },
+    PkeyChange{ port: u8 },
+    SmChange{ port: u8 },
+    ClientReregister{ port: u8 },
+    GidChange{ port: u8 },
+    DeviceFatal,
+}
+
+impl AsyncEvent{
+    // Decodes the event and returns it together with the address of the
+    // object it refers to, if any.
+    fn decode(event: &ibv_async_event) -> (AsyncEvent, Option<usize>){
+        let qp = || unsafe{ event.element.qp };
+        let qp_num = || unsafe{ (*qp()).qp_num };
+        let port = || unsafe{ event.element.port_num } as u8;
+        match event.event_type{
+            ibv_event_type::IBV_EVENT_CQ_ERR => (AsyncEvent::CqError, Some(unsafe{ event.element.cq } as usize)),
+            ibv_event_type::IBV_EVENT_QP_FATAL => (AsyncEvent::QpFatal{ qp_num: qp_num() }, Some(qp() as usize)),
+            ibv_event_type::IBV_EVENT_QP_REQ_ERR => (AsyncEvent::QpRequestError{ qp_num: qp_num() }, Some(qp() as usize)),
+            ibv_event_type::IBV_EVENT_QP_ACCESS_ERR => (AsyncEvent::QpAccessError{ qp_num: qp_num() }, Some(qp() as usize)),
+            ibv_event_type::IBV_EVENT_COMM_EST => (AsyncEvent::CommEstablished{ qp_num: qp_num() }, Some(qp() as usize)),
+            ibv_event_type::IBV_EVENT_SQ_DRAINED => (AsyncEvent::SqDrained{ qp_num: qp_num() }, Some(qp() as usize)),
+            ibv_event_type::IBV_EVENT_PATH_MIG => (AsyncEvent::PathMigrated{ qp_num: qp_num() }, Some(qp() as usize)),
+            ibv_event_type::IBV_EVENT_PATH_MIG_ERR => (AsyncEvent::PathMigrationError{ qp_num: qp_num() }, Some(qp() as usize)),
+            ibv_event_type::IBV_EVENT_QP_LAST_WQE_REACHED => (AsyncEvent::QpLastWqeReached{ qp_num: qp_num() }, Some(qp() as usize)),
+            ibv_event_type::IBV_EVENT_SRQ_ERR => (AsyncEvent::SrqError, Some(unsafe{ event.element.srq } as usize)),
+            ibv_event_type::IBV_EVENT_SRQ_LIMIT_REACHED => (AsyncEvent::SrqLimitReached, Some(unsafe{ event.element.srq } as usize)),
+            ibv_event_type::IBV_EVENT_WQ_FATAL => (AsyncEvent::WqFatal, None),
+            ibv_event_type::IBV_EVENT_PORT_ACTIVE => (AsyncEvent::PortActive{ port: port() }, None),
+            ibv_event_type::IBV_EVENT_PORT_ERR => (AsyncEvent::PortError{ port: port() }, None),
+            ibv_event_type::IBV_EVENT_LID_CHANGE => (AsyncEvent::LidChange{ port: port() }, None),
+            ibv_event_type::IBV_EVENT_PKEY_CHANGE => (AsyncEvent::PkeyChange{ port: port() }, None),
+            ibv_event_type::IBV_EVENT_SM_CHANGE => (AsyncEvent::SmChange{ port: port() }, None),
+            ibv_event_type::IBV_EVENT_CLIENT_REREGISTER => (AsyncEvent::ClientReregister{ port: port() }, None),
+            ibv_event_type::IBV_EVENT_GID_CHANGE => (AsyncEvent::GidChange{ port: port() }, None),
+            ibv_event_type::IBV_EVENT_DEVICE_FATAL => (AsyncEvent::DeviceFatal, None),
+        }
+    }
+    /// The QP the event refers to, if it is a QP event.
+    pub fn qp_num(&self) -> Option<u32>{
+        match *self{
+            AsyncEvent::QpFatal{ qp_num } |
+            AsyncEvent::QpRequestError{ qp_num } |
+            AsyncEvent::QpAccessError{ qp_num } |
+            AsyncEvent::CommEstablished{ qp_num } |
+            AsyncEvent::SqDrained{ qp_num } |
+            AsyncEvent::PathMigrated{ qp_num } |
+            AsyncEvent::PathMigrationError{ qp_num } |
+            AsyncEvent::QpLastWqeReached{ qp_num } => Some(qp_num),
+            _ => None,
+        }
+    }
+    /// The port the event refers to, if it is a port event.
+    pub fn port(&self) -> Option<u8>{
+        match *self{
+            AsyncEvent::PortActive{ port } |
+            AsyncEvent::PortError{ port } |
+            AsyncEvent::LidChange{ port } |
+            AsyncEvent::PkeyChange{ port } |
+            AsyncEvent::SmChange{ port } |
+            AsyncEvent::ClientReregister{ port } |
+            AsyncEvent::GidChange{ port } => Some(port),
+            _ => None,
+        }
+    }
+    /// Whether the event leaves the QP, CQ, SRQ or device unusable.
+    pub fn is_fatal(&self) -> bool{
+        matches!(self,
+            AsyncEvent::CqError |
+            AsyncEvent::QpFatal{ .. } |
+            AsyncEvent::QpRequestError{ .. } |
+            AsyncEvent::QpAccessError{ .. } |
+            AsyncEvent::SrqError |
+            AsyncEvent::WqFatal |
+            AsyncEvent::DeviceFatal
+        )
+    }
+}
+
+pub type AsyncEventCallback = Arc<dyn Fn(&AsyncEvent) + Send + Sync>;
+
+// Callbacks of QPs, CQs and SRQs, keyed by the address of the verbs object.
+// Entries are removed when the wrapper is dropped, before the object is
+// destroyed, so an address is never matched to a stale callback.
+fn object_callbacks() -> &'static Mutex<HashMap<usize, Vec<AsyncEventCallback>>>{
+    static CALLBACKS: OnceLock<Mutex<HashMap<usize, Vec<AsyncEventCallback>>>> = OnceLock::new();
+    CALLBACKS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub(crate) fn register_object_callback(object: usize, callback: AsyncEventCallback){
+    object_callbacks().lock().unwrap().entry(object).or_default().push(callback);
+}
+
+pub(crate) fn unregister_object_callbacks(object: usize){
+    object_callbacks().lock().unwrap().remove(&object);
+}
+
+/// Reads the next async event of `context`, acknowledges it and hands it to
+/// the callbacks registered on the object it refers to.
+pub(crate) fn get_async_event(context: *mut ibv_context, timeout: Option<Duration>) -> Result<Option<AsyncEvent>>{
+    let mut pollfd = libc::pollfd{
+        fd: unsafe{ (*context).async_fd },
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms = match timeout{
+        Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+        None => -1,
+    };
+    let ret = unsafe{ libc::poll(&mut pollfd, 1, timeout_ms) };
+    if ret < 0 {
+        let err = IbvError::last_os_error("poll");
+        if err.errno() == Some(libc::EINTR) {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    if ret == 0 {
+        return Ok(None);
+    }
+    let mut raw_event = unsafe{ std::mem::zeroed::<ibv_async_event>() };
+    let ret = unsafe{ ibv_get_async_event(context, &mut raw_event) };
+    if ret != 0 {
+        return Err(IbvError::from_ret("ibv_get_async_event", ret).with_device(device_name(context)));
+    }
+    let (event, object) = AsyncEvent::decode(&raw_event);
+    // Acknowledge before running callbacks, destroying the object waits for it.
+    unsafe{ ibv_ack_async_event(&mut raw_event) };
+    if let Some(object) = object{
+        let callbacks = object_callbacks().lock().unwrap().get(&object).cloned().unwrap_or_default();
+        for callback in callbacks{
+            callback(&event);
+        }
+    }
+    Ok(Some(event))
+}
+
+#[derive(Default)]
+struct Subscribers{
+    callbacks: Vec<AsyncEventCallback>,
+    channels: Vec<mpsc::Sender<AsyncEvent>>,
+}
+
+struct ContextPtr(*mut ibv_context);
+
+unsafe impl Send for ContextPtr{}
+
+/// Reads async events of a context on a background thread and passes them
+/// to subscribers. Events of QPs, CQs and SRQs also go to the callbacks
+/// registered with `on_async_event` on those objects.
+///
+/// The thread is stopped when the dispatcher is dropped, which has to
+/// happen before the context is closed.
+pub struct AsyncEventDispatcher<'a>{
+    subscribers: Arc<Mutex<Subscribers>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    _context: PhantomData<&'a IbvContext>,
+}
+
+impl<'a> AsyncEventDispatcher<'a>{
+    pub fn new(context: &'a IbvContext) -> Result<Self>{
+        let subscribers = Arc::new(Mutex::new(Subscribers::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let context_ptr = ContextPtr(context.as_ptr());
+        let thread_subscribers = subscribers.clone();
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name("ibv-async-events".to_string())
+            .spawn(move || {
+                let context_ptr = context_ptr;
+                while !thread_stop.load(Ordering::Acquire){
+                    let event = match get_async_event(context_ptr.0, Some(DISPATCH_POLL_INTERVAL)){
+                        Ok(Some(event)) => event,
+                        Ok(None) => continue,
+                        Err(err) => {
+                            warn!("Stopping async event dispatcher: {}", err);
+                            break;
+                        },
+                    };
+                    info!("Async event: {:?}", event);
+                    // Callbacks run without the lock, so they can subscribe.
+                    let callbacks = thread_subscribers.lock().unwrap().callbacks.clone();
+                    for callback in callbacks{
+                        callback(&event);
+                    }
+                    thread_subscribers.lock().unwrap().channels.retain(|tx| tx.send(event).is_ok());
+                }
+            })
+            .map_err(|err| IbvError::verb("thread::spawn", err.raw_os_error().unwrap_or(libc::EAGAIN)))?;
+        Ok(AsyncEventDispatcher{
+            subscribers,
+            stop,
+            thread: Some(thread),
+            _context: PhantomData,
+        })
+    }
+    /// Calls `callback` on the dispatcher thread for every event.
+    pub fn on_event<F: Fn(&AsyncEvent) + Send + Sync + 'static>(&self, callback: F){
+        self.subscribers.lock().unwrap().callbacks.push(Arc::new(callback));
+    }
+    /// Returns a channel receiving every event from now on.
+    pub fn subscribe(&self) -> mpsc::Receiver<AsyncEvent>{
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().channels.push(tx);
+        rx
+    }
+}
+
+impl Drop for AsyncEventDispatcher<'_>{
+    fn drop(&mut self){
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take(){
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::{ops::{Deref, DerefMut}, ptr};
+use log::{info, warn};
+
+use crate::{IbvError, Result};
+
+const MAP_HUGE_SHIFT: i32 = 26;
+const MPOL_BIND: i32 = 2;
+const THP_SIZE: usize = 2 << 20;
+
+/// Size of the hugepages backing a `HugeBuffer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HugePageSize{
+    #[default]
+    Size2M,
+    Size1G,
+}
+
+impl HugePageSize{
+    pub fn bytes(&self) -> usize{
+        match self{
+            HugePageSize::Size2M => 2 << 20,
+            HugePageSize::Size1G => 1 << 30,
+        }
+    }
+    fn map_flag(&self) -> i32{
+        (self.bytes().trailing_zeros() as i32) << MAP_HUGE_SHIFT
+    }
+}
+
+/// How the memory of a `HugeBuffer` ended up being backed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePageBacking{
+    /// Pages from the hugetlbfs pool, mapped with MAP_HUGETLB.
+    HugeTlb(HugePageSize),
+    /// Ordinary pages aligned to 2 MiB and advised with MADV_HUGEPAGE, which
+    /// the kernel merges into transparent hugepages when it can.
+    Transparent,
+}
+
+/// Anonymous memory backed by hugepages, so large MRs need few entries in
+/// the NIC's translation cache.
+///
+/// MAP_HUGETLB is tried first. If the hugetlbfs pool has no pages of the
+/// requested size, the buffer falls back to transparent hugepages. The
+/// memory starts out zeroed and is unmapped on drop. Register it with
+/// `IbvMr::from_huge_buffer`.
+pub struct HugeBuffer{
+    addr: *mut u8,
+    len: usize,
+    mapped_len: usize,
+    backing: HugePageBacking,
+    numa_node: Option<u32>,
+}
+
+impl HugeBuffer{
+    pub fn new(len: usize, page_size: HugePageSize) -> Result<Self>{
+        HugeBuffer::allocate(len, page_size, None)
+    }
+    /// Allocates the buffer on NUMA node `node`, typically the node of the
+    /// device from `IbvContext::numa_node`.
+    pub fn on_node(len: usize, page_size: HugePageSize, node: u32) -> Result<Self>{
+        HugeBuffer::allocate(len, page_size, Some(node))
+    }
+    fn allocate(len: usize, page_size: HugePageSize, numa_node: Option<u32>) -> Result<Self>{
+        if len == 0 {
+            return Err(IbvError::invalid_argument("HugeBuffer::new", "length is 0"));
+        }
+        let (addr, mapped_len, backing) = match map_hugetlb(len, page_size){
+            Ok((addr, mapped_len)) => (addr, mapped_len, HugePageBacking::HugeTlb(page_size)),
+            Err(err) => {
+                warn!("MAP_HUGETLB with {:?} pages failed, falling back to transparent hugepages: {}", page_size, err);
+                let (addr, mapped_len) = map_transparent(len)?;
+                (addr, mapped_len, HugePageBacking::Transparent)
+            },
+        };
+        let buffer = HugeBuffer{
+            addr,
+            len,
+            mapped_len,
+            backing,
+            numa_node,
+        };
+        // The policy has to be set before the pages are first touched.
+        if let Some(node) = numa_node{
+            bind_to_node(addr, mapped_len, node)?;
+        }
+        info!("Allocated {} bytes of {:?} memory on node {:?}", mapped_len, backing, numa_node);
+        Ok(buffer)
+    }
+    pub fn as_ptr(&self) -> *const u8{
+        self.addr
+    }
+    pub fn as_mut_ptr(&mut self) -> *mut u8{
+        self.addr
+    }
+    pub fn len(&self) -> usize{
+        self.len
+    }
+    pub fn is_empty(&self) -> bool{
+        self.len == 0
+    }
+    pub fn backing(&self) -> HugePageBacking{
+        self.backing
+    }
+    /// Whether the memory came from the hugetlbfs pool, in which case it
+    /// should be registered with `IbvAccessFlags::HUGETLB`.
+    pub fn is_hugetlb(&self) -> bool{
+        matches!(self.backing, HugePageBacking::HugeTlb(_))
+    }
+    pub fn numa_node(&self) -> Option<u32>{
+        self.numa_node
+    }
+    pub fn as_slice(&self) -> &[u8]{
+        unsafe{ std::slice::from_raw_parts(self.addr, self.len) }
+    }
+    pub fn as_mut_slice(&mut self) -> &mut [u8]{
+        unsafe{ std::slice::from_raw_parts_mut(self.addr, self.len) }
+    }
+}
+
+impl Deref for HugeBuffer{
+    type Target = [u8];
+    fn deref(&self) -> &[u8]{
+        self.as_slice()
+    }
+}
+
+impl DerefMut for HugeBuffer{
+    fn deref_mut(&mut self) -> &mut [u8]{
+        self.as_mut_slice()
+    }
+}
+
+impl Drop for HugeBuffer{
+    fn drop(&mut self){
+        unsafe{ libc::munmap(self.addr as *mut libc::c_void, self.mapped_len) };
+    }
+}
+
+unsafe impl Send for HugeBuffer{}
+unsafe impl Sync for HugeBuffer{}
+
+fn round_up(len: usize, align: usize) -> usize{
+    len.div_ceil(align) * align
+}
+
+fn map_hugetlb(len: usize, page_size: HugePageSize) -> Result<(*mut u8, usize)>{
+    let mapped_len = round_up(len, page_size.bytes());
+    let addr = unsafe{ libc::mmap(
+        ptr::null_mut(),
+        mapped_len,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | page_size.map_flag(),
+        -1,
+        0,
+    ) };
+    if addr == libc::MAP_FAILED {
+        return Err(IbvError::last_os_error("mmap"));
+    }
+    Ok((addr as *mut u8, mapped_len))
+}
+
+// Maps ordinary pages on a 2 MiB boundary, so the kernel can back them with
+// transparent hugepages.
+fn map_transparent(len: usize) -> Result<(*mut u8, usize)>{
+    let mapped_len = round_up(len, THP_SIZE);
+    let reserved_len = mapped_len + THP_SIZE;
+    let reserved = unsafe{ libc::mmap(
+        ptr::null_mut(),
+        reserved_len,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        -1,
+        0,
+    ) };
+    if reserved == libc::MAP_FAILED {
+        return Err(IbvError::last_os_error("mmap"));
+    }
+    // Trim the reservation down to the aligned part.
+    let reserved = reserved as usize;
+    let addr = round_up(reserved, THP_SIZE);
+    let head = addr - reserved;
+    let tail = reserved_len - head - mapped_len;
+    unsafe{
+        if head > 0 {
+            libc::munmap(reserved as *mut libc::c_void, head);
+        }
+        if tail > 0 {
+            libc::munmap((addr + mapped_len) as *mut libc::c_void, tail);
+        }
+    }
+    let ret = unsafe{ libc::madvise(addr as *mut libc::c_void, mapped_len, libc::MADV_HUGEPAGE) };
+    if ret != 0 {
+        warn!("madvise(MADV_HUGEPAGE) failed: {}", std::io::Error::last_os_error());
+    }
+    Ok((addr as *mut u8, mapped_len))
+}
+
+fn bind_to_node(addr: *mut u8, len: usize, node: u32) -> Result<()>{
+    let bits = u64::BITS as usize;
+    let mut nodemask = vec![0u64; node as usize / bits + 1];
+    nodemask[node as usize / bits] |= 1 << (node as usize % bits);
+    let maxnode = nodemask.len() * bits + 1;
+    let ret = unsafe{ libc::syscall(
+        libc::SYS_mbind,
+        addr as *mut libc::c_void,
+        len,
+        MPOL_BIND,
+        nodemask.as_ptr(),
+        maxnode,
+        0,
+    ) };
+    if ret != 0 {
+        return Err(IbvError::last_os_error("mbind"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn lengths_round_up_to_whole_pages(){
+        assert_eq!(round_up(1, THP_SIZE), THP_SIZE);
+        assert_eq!(round_up(THP_SIZE, THP_SIZE), THP_SIZE);
+        assert_eq!(round_up(THP_SIZE + 1, THP_SIZE), 2 * THP_SIZE);
+        assert_eq!(round_up(3 << 30, HugePageSize::Size1G.bytes()), 3 << 30);
+    }
+
+    #[test]
+    fn map_flag_encodes_page_size(){
+        // MAP_HUGE_2MB and MAP_HUGE_1GB from the kernel headers.
+        assert_eq!(HugePageSize::Size2M.map_flag(), 21 << MAP_HUGE_SHIFT);
+        assert_eq!(HugePageSize::Size1G.map_flag(), 30 << MAP_HUGE_SHIFT);
+    }
+
+    #[test]
+    fn empty_buffer_is_rejected(){
+        assert!(matches!(HugeBuffer::new(0, HugePageSize::Size2M), Err(IbvError::InvalidArgument{ .. })));
+    }
+
+    #[test]
+    fn buffer_is_usable_whatever_the_backing(){
+        let mut buf = HugeBuffer::new(4096, HugePageSize::Size2M).unwrap();
+        assert_eq!(buf.len(), 4096);
+        if buf.backing() == HugePageBacking::Transparent {
+            assert_eq!(buf.as_ptr() as usize % THP_SIZE, 0);
+        }
+        buf.as_mut_slice().fill(0xab);
+        assert!(buf.iter().all(|&byte| byte == 0xab));
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use crate::{IbvError, IbvQp, IbvSendFlags, IbvSendWr, IbvSge, IbvWrOpcode, MrPool, PendingWr, PoolBuf, Result, WorkCompletion};
+
+impl IbvQp{
+    /// Sends `data` without the caller registering it. Payloads up to the
+    /// granted `max_inline_data` are copied into the WR with
+    /// `IbvSendFlags::INLINE`, so `data` can be reused as soon as this
+    /// returns. Larger ones are copied into a buffer from `pool`, which the
+    /// returned handle holds until the send completes.
+    pub fn send(&self, wr_id: u64, data: &[u8], pool: &MrPool) -> Result<PendingSend>{
+        if data.len() <= self.cap.max_inline_data as usize {
+            let pending = PendingWr::post(wr_id, self.send_cq(), || self.post_send_inline(wr_id, data, IbvSendFlags::SIGNALED))?;
+            return Ok(PendingSend{
+                wr_id,
+                pending: Some(pending),
+                buf: None,
+            });
+        }
+        let mut buf = pool.alloc(data.len())?;
+        buf.as_mut_slice().copy_from_slice(data);
+        let send_wr = IbvSendWr::new(wr_id, vec![buf.sge()], IbvWrOpcode::Send, IbvSendFlags::SIGNALED, 0, 0);
+        let pending = PendingWr::post(wr_id, self.send_cq(), || self.ibv_post_send(send_wr))?;
+        Ok(PendingSend{
+            wr_id,
+            pending: Some(pending),
+            buf: Some(buf),
+        })
+    }
+    /// Posts `data` as an inline SEND with `send_flags`. The provider copies
+    /// the payload while posting, so no MR is needed. Fails with EINVAL if
+    /// it exceeds the granted `max_inline_data`.
+    pub fn post_send_inline(&self, wr_id: u64, data: &[u8], send_flags: IbvSendFlags) -> Result<()>{
+        if data.len() > self.cap.max_inline_data as usize {
+            return Err(IbvError::invalid_argument("IbvQp::post_send_inline", format!("{} bytes for WR {} exceed max_inline_data {}", data.len(), wr_id, self.cap.max_inline_data)));
+        }
+        // The lkey of an inline SGE is ignored.
+        let sg_list = if data.is_empty() { Vec::new() } else { vec![IbvSge::new(data.as_ptr() as u64, data.len() as u32, 0)] };
+        let send_wr = IbvSendWr::new(wr_id, sg_list, IbvWrOpcode::Send, send_flags | IbvSendFlags::INLINE, 0, 0);
+        self.ibv_post_send(send_wr)
+    }
+}
+
+/// A send posted by `IbvQp::send`. If its payload did not fit inline, the
+/// pool buffer holding it is released once the send completes. Dropping
+/// the handle before that does not block; the buffer is released when the
+/// completion is reaped by a later wait or poll on the send CQ.
+#[must_use = "the send is only known to be done once its completion is reaped"]
+pub struct PendingSend{
+    wr_id: u64,
+    pending: Option<PendingWr<'static>>,
+    buf: Option<PoolBuf>,
+}
+
+impl PendingSend{
+    pub fn wr_id(&self) -> u64{
+        self.wr_id
+    }
+    /// Whether the payload was sent inline.
+    pub fn is_inline(&self) -> bool{
+        self.buf.is_none()
+    }
+    /// Blocks until the send completes, see `PendingWr::wait`.
+    pub fn wait(mut self) -> Result<WorkCompletion>{
+        self.wait_for(None)
+    }
+    /// Like `wait`, but fails with ETIMEDOUT after `timeout`, after which
+    /// it can be called again.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<WorkCompletion>{
+        self.wait_for(Some(timeout))
+    }
+    fn wait_for(&mut self, timeout: Option<Duration>) -> Result<WorkCompletion>{
+        let pending = self.pending.as_mut().ok_or_else(|| IbvError::invalid_argument("PendingSend::wait", "the send already completed"))?;
+        let result = pending.wait_for(timeout);
+        if !matches!(&result, Err(err) if err.errno() == Some(libc::ETIMEDOUT)) {
+            self.pending = None;
+        }
+        result
+    }
+}
+
+impl Drop for PendingSend{
+    fn drop(&mut self){
+        // The device may still be reading the buffer.
+        if let Some(pending) = self.pending.take(){
+            pending.abandon(self.buf.take().map(|buf| Box::new(buf) as Box<dyn Send>));
+        }
+    }
+}
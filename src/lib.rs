@@ -1,13 +1,20 @@
-use std::{collections::BTreeMap, ffi::CStr, fs, net::{IpAddr, Ipv4Addr, Ipv6Addr}, ops::BitOr, path::PathBuf, ptr::{self, null_mut, NonNull}};
-use log::info;
+use std::{collections::{BTreeMap, HashMap}, ffi::CStr, fs, marker::PhantomData, net::{IpAddr, Ipv4Addr, Ipv6Addr}, ops::{Deref, DerefMut, Range}, os::fd::RawFd, path::PathBuf, ptr::{self, null_mut}, time::{Duration, Instant}};
+use bitflags::bitflags;
+use log::{info, warn};
 use rdma_sys::*;
 use serde::{Deserialize, Serialize};
-use std::sync::Once;
+use std::sync::{atomic::{AtomicU32, Ordering}, Arc, Mutex, Once, Weak};
 use env_logger::Env;
 //use log::LevelFilter;
 
 static INIT: Once = Once::new();
 
+const PORT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of CQ events collected before they are acknowledged in one call,
+/// as `ibv_ack_cq_events` takes a lock.
+pub const CQ_EVENT_ACK_BATCH: u32 = 16;
+
 pub fn initialize_logger() {
     INIT.call_once(|| {
         env_logger::Builder::from_env(Env::default().default_filter_or("info"))
@@ -16,60 +23,66 @@ pub fn initialize_logger() {
     });
 }
 
+pub mod batch;
+pub mod completion;
+pub mod device;
+pub mod error;
+pub mod event;
+pub mod hugepage;
+pub mod inline;
+pub mod mw;
+pub mod odp;
+pub mod pool;
+pub mod qp;
 pub mod sender;
 pub mod receiver;
+pub mod remote;
+#[cfg(feature = "tokio")]
+pub mod stream;
+
+pub use batch::{PostError, PostResult, RecvBatch, SendBatch};
+pub use completion::{WcFlags, WcOpcode, WcStatus, WorkCompletion};
+pub use device::{
+    list_devices, AtomicCap, IbvDeviceAttr, IbvDeviceInfo, IbvDeviceList, IbvPortAttr, LinkLayer, Mtu, NodeType, OdpCaps,
+    PortSpeed, PortState, PortWidth, TransportType,
+};
+pub use error::{ErrorContext, IbvError, IbvErrorKind, Result};
+pub use event::{AsyncEvent, AsyncEventCallback, AsyncEventDispatcher};
+pub use hugepage::{HugeBuffer, HugePageBacking, HugePageSize};
+pub use inline::PendingSend;
+pub use mw::{IbvMw, MwType};
+pub use odp::MrAdvice;
+pub use pool::{Exhaustion, MrPool, MrPoolBuilder, MrPoolStats, PoolBuf, SizeClassStats};
+pub use qp::{IbvQpAttr, IbvQpState, Init, Qp, QpState, Reset, Rtr, Rts};
+pub use remote::{PendingAtomic, PendingWr, RemoteAtomicU64, RemoteBuffer, RemoteSlice};
+#[cfg(feature = "tokio")]
+pub use stream::{AsyncQp, CompletionStream};
 
 pub struct IbvQp{
     inner: Box<*mut ibv_qp>,
+    pd: IbvPd,
     recv_cq: IbvCq,
     send_cq: IbvCq,
-    event_channel: IbvCompChannel,
+    srq: Option<IbvSrq>,
+    cap: IbvQpCap,
+    qp_type: IbvQpType,
+    access: IbvAccessFlags,
     psn: u32,
     gidx: i32,
     port: u8,
 }
 
 impl IbvQp{
-    pub fn new(pd: &IbvPd, context: &IbvContext, gidx: i32, port: u8) -> Self{
-        let comp_channel = IbvCompChannel::new(&context);
-        info!("comp_channel created");
-        let cq = IbvCq::new(&context, 100, &comp_channel, 0);
-        let ret = unsafe { ibv_req_notify_cq(cq.as_ptr(), 0) };
-        if ret != 0 {
-            panic!("Failed to request notify cq");
-        }
-        info!("cq created");
-        let mut qp_init_attr = ibv_qp_init_attr {
-            qp_context: null_mut(),
-            send_cq: cq.as_ptr(),
-            recv_cq: cq.as_ptr(),
-            srq: null_mut(),
-            cap: ibv_qp_cap {
-                max_send_wr: 100,
-                max_recv_wr: 100,
-                max_send_sge: 15,
-                max_recv_sge: 15,
-                max_inline_data: 64,
-            },
-            qp_type: ibv_qp_type::IBV_QPT_RC,
-            sq_sig_all: 0,
-        };
-        info!("qp_init_attr created");
-        let qp = unsafe{ ibv_create_qp(pd.as_ptr(), &mut qp_init_attr) };
-        info!("qp created: {}", unsafe {
-            (*qp).qp_num
-        });
-        let inner = Box::new(qp);
-        let psn = rand::random::<u32>() & 0xffffff;
-        IbvQp{
-            inner,
-            recv_cq: cq.clone(),
-            send_cq: cq,
-            event_channel: comp_channel,
-            psn,
-            gidx,
-            port,
+    /// Creates an RC QP with default capabilities and a private CQ. Use
+    /// `QpBuilder` to share CQs, attach an SRQ or size the queues.
+    pub fn new(pd: &IbvPd, context: &IbvContext, gidx: i32, port: u8) -> Result<Self>{
+        if pd.context() != context.as_ptr() {
+            return Err(IbvError::invalid_argument("IbvQp::new", format!("the PD does not belong to {}", context.device_name())));
         }
+        QpBuilder::new(pd)
+            .gid_index(gidx)
+            .port(port)
+            .build()
     }
     pub fn as_ptr(&self) -> *mut ibv_qp {
         *self.inner
@@ -80,20 +93,61 @@ impl IbvQp{
     pub fn psn(&self) -> u32{
         self.psn
     }
-    pub fn init(&self, port: u8) -> anyhow::Result<()>{
+    pub fn port(&self) -> u8{
+        self.port
+    }
+    pub fn gid_index(&self) -> i32{
+        self.gidx
+    }
+    /// Capabilities granted by the driver, which may exceed the requested ones.
+    pub fn cap(&self) -> IbvQpCap{
+        self.cap
+    }
+    pub fn qp_type(&self) -> IbvQpType{
+        self.qp_type
+    }
+    /// Remote access set on INIT, see `QpBuilder::access`.
+    pub fn access(&self) -> IbvAccessFlags{
+        self.access
+    }
+    pub fn pd(&self) -> &IbvPd{
+        &self.pd
+    }
+    pub fn send_cq(&self) -> &IbvCq{
+        &self.send_cq
+    }
+    pub fn recv_cq(&self) -> &IbvCq{
+        &self.recv_cq
+    }
+    pub fn srq(&self) -> Option<&IbvSrq>{
+        self.srq.as_ref()
+    }
+    /// Calls `callback` for async events of this QP, such as `QpFatal` or
+    /// `QpLastWqeReached`, when they are read from the context.
+    pub fn on_async_event<F: Fn(&AsyncEvent) + Send + Sync + 'static>(&self, callback: F){
+        event::register_object_callback(self.as_ptr() as usize, Arc::new(callback));
+    }
+    pub fn init(&self, port: u8) -> Result<()>{
         let mut qp_attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
         qp_attr.qp_state = ibv_qp_state::IBV_QPS_INIT;
         qp_attr.pkey_index = 0;
         qp_attr.port_num = port;
-        qp_attr.qp_access_flags = ibv_access_flags::IBV_ACCESS_LOCAL_WRITE.0 | ibv_access_flags::IBV_ACCESS_REMOTE_READ.0 | ibv_access_flags::IBV_ACCESS_REMOTE_WRITE.0;
+        qp_attr.qp_access_flags = self.access.bits();
         let qp_attr_mask = ibv_qp_attr_mask::IBV_QP_STATE | ibv_qp_attr_mask::IBV_QP_PKEY_INDEX | ibv_qp_attr_mask::IBV_QP_PORT | ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS;
         let ret = unsafe { ibv_modify_qp(self.as_ptr(), &mut qp_attr, qp_attr_mask.0 as i32) };
         if ret != 0 {
-            return Err(anyhow::anyhow!("Failed to modify QP"));
+            return Err(self.error("ibv_modify_qp", ret).with_port(port));
         }
         Ok(())
     }
-    pub fn connect(&self, remote_qp_metadata: &QpMetadata) -> anyhow::Result<()>{
+    /// Moves the QP through RTR to RTS. `params` should be the result of
+    /// `ConnectParams::negotiate` with the parameters sent by the peer.
+    pub fn connect(&self, remote_qp_metadata: &QpMetadata, params: &ConnectParams) -> Result<()>{
+        self.ready_to_receive(remote_qp_metadata, params)?;
+        self.ready_to_send(params)
+    }
+    /// Moves an INIT QP to RTR, addressing the remote QP.
+    pub fn ready_to_receive(&self, remote_qp_metadata: &QpMetadata, params: &ConnectParams) -> Result<()>{
         let remote_subnet_id = remote_qp_metadata.subnet_id;
         let remote_interface_id = remote_qp_metadata.interface_id;
         let subnet_prefix_bytes = remote_subnet_id.to_be_bytes();
@@ -108,24 +162,24 @@ impl IbvQp{
         };
         let remote_qpn = remote_qp_metadata.qpn;
         let remote_psn = remote_qp_metadata.psn;
-        let psn = self.psn;
         let gidx = self.gidx;
         let port = self.port;
         let mut qp_attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
         qp_attr.qp_state = ibv_qp_state::IBV_QPS_RTR;
-        qp_attr.path_mtu = ibv_mtu::IBV_MTU_4096;
+        qp_attr.path_mtu = params.path_mtu.get();
         qp_attr.dest_qp_num = remote_qpn;
         qp_attr.rq_psn = remote_psn;
-        qp_attr.max_dest_rd_atomic = 1;
-        qp_attr.min_rnr_timer = 12;
-        qp_attr.ah_attr.sl = 0;
+        qp_attr.max_dest_rd_atomic = params.max_dest_rd_atomic;
+        qp_attr.min_rnr_timer = params.min_rnr_timer;
+        qp_attr.ah_attr.sl = params.sl;
         qp_attr.ah_attr.src_path_bits = 0;
         qp_attr.ah_attr.port_num = port;
         qp_attr.ah_attr.dlid = 0;
         qp_attr.ah_attr.grh.dgid = remote_gid;
         qp_attr.ah_attr.is_global = 1;
         qp_attr.ah_attr.grh.sgid_index = gidx as u8;
-        qp_attr.ah_attr.grh.hop_limit = 10;
+        qp_attr.ah_attr.grh.hop_limit = params.hop_limit;
+        qp_attr.ah_attr.grh.traffic_class = params.traffic_class;
         let qp_attr_mask = 
             ibv_qp_attr_mask::IBV_QP_STATE |
             ibv_qp_attr_mask::IBV_QP_AV |
@@ -136,14 +190,24 @@ impl IbvQp{
             ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER;
         let ret = unsafe { ibv_modify_qp(self.as_ptr(), &mut qp_attr, qp_attr_mask.0 as i32) };
         if ret != 0 {
-            return Err(anyhow::anyhow!("Failed to modify QP to RTR"));
+            // A down link is the most common reason for RTR to fail, report
+            // it as such instead of a bare EINVAL.
+            self.check_port_active()?;
+            return Err(self.error("ibv_modify_qp(RTR)", ret).with_port(port));
         }
+        Ok(())
+    }
+    /// Moves an RTR QP to RTS, after which sends can be posted.
+    pub fn ready_to_send(&self, params: &ConnectParams) -> Result<()>{
+        let psn = self.psn;
+        let port = self.port;
+        let mut qp_attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
         qp_attr.qp_state = ibv_qp_state::IBV_QPS_RTS;
-        qp_attr.timeout = 14;
-        qp_attr.retry_cnt = 7;
-        qp_attr.rnr_retry = 7;
+        qp_attr.timeout = params.timeout;
+        qp_attr.retry_cnt = params.retry_cnt;
+        qp_attr.rnr_retry = params.rnr_retry;
         qp_attr.sq_psn = psn;
-        qp_attr.max_rd_atomic = 1;
+        qp_attr.max_rd_atomic = params.max_rd_atomic;
         let qp_attr_mask = 
             ibv_qp_attr_mask::IBV_QP_STATE |
             ibv_qp_attr_mask::IBV_QP_TIMEOUT |
@@ -153,64 +217,105 @@ impl IbvQp{
             ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC;
         let ret = unsafe { ibv_modify_qp(self.as_ptr(), &mut qp_attr, qp_attr_mask.0 as i32) };
         if ret != 0 {
-            return Err(anyhow::anyhow!("Failed to modify QP to RTS"));
+            return Err(self.error("ibv_modify_qp(RTS)", ret).with_port(port));
         }
         Ok(())
     }
-    pub fn ibv_post_send(&self, send_wr: IbvSendWr) -> anyhow::Result<()>{
+    pub fn ibv_post_send(&self, send_wr: IbvSendWr) -> Result<()>{
+        if let Err(wr_id) = send_wr.check_num_sge(self.cap.max_send_sge){
+            return Err(self.error("ibv_post_send", libc::EINVAL).with_wr_id(wr_id));
+        }
         let mut bad_wr: *mut ibv_send_wr = ptr::null_mut();
         let ret = unsafe{ ibv_post_send(self.as_ptr(), send_wr.as_ptr(), &mut bad_wr) };
         if ret != 0 {
-            return Err(anyhow::anyhow!("Failed to post send"));
+            let wr_id = unsafe { bad_wr.as_ref() }.map_or(send_wr.wr_id(), |wr| wr.wr_id);
+            return Err(self.error("ibv_post_send", ret).with_wr_id(wr_id));
         }
         Ok(())
     }
-    pub fn ibv_post_recv(&self, recv_wr: IbvRecvWr) -> anyhow::Result<()>{
+    pub fn ibv_post_recv(&self, recv_wr: IbvRecvWr) -> Result<()>{
+        if let Err(wr_id) = recv_wr.check_num_sge(self.cap.max_recv_sge){
+            return Err(self.error("ibv_post_recv", libc::EINVAL).with_wr_id(wr_id));
+        }
         let mut bad_wr: *mut ibv_recv_wr = ptr::null_mut();
         let ret = unsafe { ibv_post_recv(self.as_ptr(), recv_wr.as_ptr(), &mut bad_wr) };
         if ret != 0 {
-            println!("Error posting recv: {}", ret);
+            let wr_id = unsafe { bad_wr.as_ref() }.map_or(recv_wr.wr_id(), |wr| wr.wr_id);
+            return Err(self.error("ibv_post_recv", ret).with_wr_id(wr_id));
         }
         Ok(())
     }
-    pub fn wait_for_event(&self) -> anyhow::Result<()>{
-        let event_channel = self.event_channel.as_ptr();
-        let mut recv_cq = self.recv_cq.as_ptr();
-        let mut cq = unsafe { std::mem::zeroed::<*mut ibv_cq>() };
+    /// Waits for the next completion on the receive CQ and returns it, or
+    /// an error if it did not complete successfully.
+    pub fn wait_for_event(&self) -> Result<WorkCompletion>{
+        self.wait_for_completion(None)
+    }
+    /// Like `wait_for_event`, but fails with ETIMEDOUT after `timeout`.
+    pub fn wait_for_event_timeout(&self, timeout: Duration) -> Result<WorkCompletion>{
+        self.wait_for_completion(Some(timeout))
+    }
+    fn wait_for_completion(&self, timeout: Option<Duration>) -> Result<WorkCompletion>{
+        let mut wc = [WorkCompletion::default()];
         info!("Waiting for cq event");
-        
-        let mut context = null_mut();
-        let ret = unsafe{ ibv_get_cq_event(event_channel, &mut cq, &mut context) };
-        if ret != 0 {
-            return Err(anyhow::anyhow!("Failed to get cq event"));
+        if self.recv_cq.wait(&mut wc, timeout)? == 0 {
+            return Err(self.error("ibv_get_cq_event", libc::ETIMEDOUT));
         }
-        
-        info!("Requesting notify cq");
-        let ret = unsafe{ ibv_req_notify_cq(recv_cq, 0) };
+        let [wc] = wc;
+        wc.result()?;
+        Ok(wc)
+    }
+    pub fn query(&self) -> Result<IbvQpAttr>{
+        IbvQpAttr::query(self.as_ptr()).map_err(|ret| self.error("ibv_query_qp", ret))
+    }
+    pub fn state(&self) -> Result<IbvQpState>{
+        let state = self.query()?.state;
+        info!("QP state: {}", state);
+        Ok(state)
+    }
+    /// Moves the QP to RESET and picks a fresh PSN. All outstanding work
+    /// requests are discarded.
+    pub fn reset(&mut self) -> Result<()>{
+        let mut qp_attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        qp_attr.qp_state = ibv_qp_state::IBV_QPS_RESET;
+        let ret = unsafe { ibv_modify_qp(self.as_ptr(), &mut qp_attr, ibv_qp_attr_mask::IBV_QP_STATE.0 as i32) };
         if ret != 0 {
-            return Err(anyhow::anyhow!("Failed to request notify cq"));
-        }
-        let mut wc = unsafe { std::mem::zeroed::<ibv_wc>() };
-        info!("Polling cq");
-        let ret = unsafe{ ibv_poll_cq(recv_cq, 1, &mut wc) };
-        if ret != 1 {
-            return Err(anyhow::anyhow!("Failed to poll cq"));
-        }
-        if wc.status != ibv_wc_status::IBV_WC_SUCCESS {
-            return Err(anyhow::anyhow!("Failed to get success status"));
+            return Err(self.error("ibv_modify_qp(RESET)", ret));
         }
+        self.psn = rand::random::<u32>() & 0xffffff;
         Ok(())
     }
-    pub fn state(&self) -> anyhow::Result<()>{
-        let state = unsafe{ (*self.as_ptr()).state };
-        info!("QP state: {:?}", state);
+    /// Brings a QP, typically in ERROR after retries were exceeded, back to
+    /// INIT with a fresh PSN. Exchange the new PSN with the peer, then call
+    /// `connect` again.
+    pub fn recover(&mut self) -> Result<()>{
+        info!("Recovering QP {} from state {}", self.qp_num(), self.state()?);
+        self.reset()?;
+        self.init(self.port)
+    }
+    fn check_port_active(&self) -> Result<()>{
+        let context = unsafe{ (*self.as_ptr()).context };
+        let port_attr = IbvPortAttr::query(context, self.port)?;
+        if !port_attr.is_active() {
+            return Err(IbvError::PortNotActive{
+                device: device_name(context),
+                port: self.port,
+                state: port_attr.state,
+            });
+        }
         Ok(())
     }
+    fn error(&self, verb: &'static str, ret: i32) -> IbvError{
+        let context = unsafe{ (*self.as_ptr()).context };
+        IbvError::from_ret(verb, ret)
+            .with_device(device_name(context))
+            .with_qp(self.qp_num())
+    }
 }
 
 impl Drop for IbvQp{
     fn drop(&mut self){
         info!("Destroying QP");
+        event::unregister_object_callbacks(self.as_ptr() as usize);
         unsafe{ ibv_destroy_qp(self.as_ptr()) };
     }
 }
@@ -253,6 +358,12 @@ impl IbvQpInitAttr{
         }
     }
 
+    pub fn set_srq(&mut self, srq: &IbvSrq){
+        self.inner.srq = srq.as_ptr();
+    }
+    pub fn cap(&self) -> IbvQpCap{
+        self.inner.cap.into()
+    }
     pub fn as_ptr(&self) -> *mut ibv_qp_init_attr{
         &self.inner as *const _ as *mut _
     }
@@ -264,6 +375,239 @@ impl IbvQpInitAttr{
 unsafe impl Send for IbvQpInitAttr{}
 unsafe impl Sync for IbvQpInitAttr{}
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IbvQpCap{
+    pub max_send_wr: u32,
+    pub max_recv_wr: u32,
+    pub max_send_sge: u32,
+    pub max_recv_sge: u32,
+    pub max_inline_data: u32,
+}
+
+impl Default for IbvQpCap{
+    fn default() -> Self{
+        IbvQpCap{
+            max_send_wr: 100,
+            max_recv_wr: 100,
+            max_send_sge: 15,
+            max_recv_sge: 15,
+            max_inline_data: 64,
+        }
+    }
+}
+
+impl IbvQpCap{
+    /// Lowers each limit to what the device supports.
+    pub fn clamp_to(&self, device_attr: &IbvDeviceAttr) -> Self{
+        IbvQpCap{
+            max_send_wr: self.max_send_wr.min(device_attr.max_qp_wr),
+            max_recv_wr: self.max_recv_wr.min(device_attr.max_qp_wr),
+            max_send_sge: self.max_send_sge.min(device_attr.max_sge),
+            max_recv_sge: self.max_recv_sge.min(device_attr.max_sge),
+            max_inline_data: self.max_inline_data,
+        }
+    }
+}
+
+impl From<ibv_qp_cap> for IbvQpCap{
+    fn from(cap: ibv_qp_cap) -> Self{
+        IbvQpCap{
+            max_send_wr: cap.max_send_wr,
+            max_recv_wr: cap.max_recv_wr,
+            max_send_sge: cap.max_send_sge,
+            max_recv_sge: cap.max_recv_sge,
+            max_inline_data: cap.max_inline_data,
+        }
+    }
+}
+
+/// Builds a QP on an existing PD.
+///
+/// CQs that are not given are replaced by a private CQ with its own
+/// completion channel, shared by the send and receive queue. When no
+/// capabilities are set, the defaults are clamped to the device limits.
+/// The inline size is halved until the device accepts it.
+pub struct QpBuilder<'a>{
+    pd: &'a IbvPd,
+    send_cq: Option<&'a IbvCq>,
+    recv_cq: Option<&'a IbvCq>,
+    srq: Option<&'a IbvSrq>,
+    qp_type: IbvQpType,
+    cap: Option<IbvQpCap>,
+    sq_sig_all: bool,
+    max_inline_data: Option<u32>,
+    access: IbvAccessFlags,
+    gidx: i32,
+    port: u8,
+    completion_mode: CompletionMode,
+}
+
+impl<'a> QpBuilder<'a>{
+    pub fn new(pd: &'a IbvPd) -> Self{
+        QpBuilder{
+            pd,
+            send_cq: None,
+            recv_cq: None,
+            srq: None,
+            qp_type: IbvQpType::Rc,
+            cap: None,
+            sq_sig_all: false,
+            max_inline_data: None,
+            access: IbvAccessFlags::local_and_remote(),
+            gidx: 0,
+            port: 1,
+            completion_mode: CompletionMode::default(),
+        }
+    }
+    pub fn send_cq(mut self, cq: &'a IbvCq) -> Self{
+        self.send_cq = Some(cq);
+        self
+    }
+    pub fn recv_cq(mut self, cq: &'a IbvCq) -> Self{
+        self.recv_cq = Some(cq);
+        self
+    }
+    /// Uses the same CQ for send and receive completions.
+    pub fn cq(self, cq: &'a IbvCq) -> Self{
+        self.send_cq(cq).recv_cq(cq)
+    }
+    pub fn srq(mut self, srq: &'a IbvSrq) -> Self{
+        self.srq = Some(srq);
+        self
+    }
+    pub fn qp_type(mut self, qp_type: IbvQpType) -> Self{
+        self.qp_type = qp_type;
+        self
+    }
+    pub fn cap(mut self, cap: IbvQpCap) -> Self{
+        self.cap = Some(cap);
+        self
+    }
+    /// Generate a completion for every send WR, not only signaled ones.
+    pub fn sq_sig_all(mut self, sq_sig_all: bool) -> Self{
+        self.sq_sig_all = sq_sig_all;
+        self
+    }
+    /// The inline size to ask for, overriding the one in `cap`. If the
+    /// device cannot provide it, the largest power-of-two fraction it
+    /// accepts is used; `IbvQp::cap` reports what was granted.
+    pub fn max_inline_data(mut self, max_inline_data: u32) -> Self{
+        self.max_inline_data = Some(max_inline_data);
+        self
+    }
+    /// What the peer may do to this side's memory through the QP, set when
+    /// it moves to INIT. Defaults to `IbvAccessFlags::local_and_remote`.
+    /// `IbvAccessFlags::REMOTE_ATOMIC` is dropped with a warning if the
+    /// device has no atomic support; `IbvQp::access` reports what was set.
+    pub fn access(mut self, access: IbvAccessFlags) -> Self{
+        self.access = access;
+        self
+    }
+    pub fn gid_index(mut self, gidx: i32) -> Self{
+        self.gidx = gidx;
+        self
+    }
+    pub fn port(mut self, port: u8) -> Self{
+        self.port = port;
+        self
+    }
+    /// How the private CQ is waited on. Caller-provided CQs keep their own
+    /// mode, see `IbvCq::set_completion_mode`.
+    pub fn completion_mode(mut self, completion_mode: CompletionMode) -> Self{
+        self.completion_mode = completion_mode;
+        self
+    }
+    /// Builds the QP in the RESET state with compile time state tracking.
+    pub fn build_typed(self) -> Result<Qp<Reset>>{
+        Qp::new(self)
+    }
+    pub fn build(self) -> Result<IbvQp>{
+        let context = self.pd.context();
+        let cap = match self.cap{
+            Some(cap) => cap,
+            None => IbvQpCap::default().clamp_to(&IbvDeviceAttr::query(context)?),
+        };
+        let mut access = self.access;
+        if access.contains(IbvAccessFlags::REMOTE_ATOMIC) && !IbvDeviceAttr::query(context)?.atomic_cap.is_supported() {
+            warn!("{} has no atomic support, not enabling remote atomics", device_name(context));
+            access.remove(IbvAccessFlags::REMOTE_ATOMIC);
+        }
+        let private_cq = match (self.send_cq, self.recv_cq){
+            (Some(_), Some(_)) => None,
+            _ => {
+                let cqe = (cap.max_send_wr + cap.max_recv_wr).max(1) as i32;
+                // A spinning CQ never sleeps, so it does not need a channel.
+                let channel = match self.completion_mode{
+                    CompletionMode::Spin => None,
+                    _ => {
+                        let channel = IbvCompChannel::from_raw_context(context)?;
+                        info!("comp_channel created");
+                        Some(channel)
+                    },
+                };
+                let cq = IbvCq::create(context, cqe, channel.as_ref(), 0)?;
+                cq.set_completion_mode(self.completion_mode);
+                info!("cq created");
+                Some(cq)
+            },
+        };
+        let send_cq = self.send_cq.or(private_cq.as_ref()).cloned().expect("send CQ is set");
+        let recv_cq = self.recv_cq.or(private_cq.as_ref()).cloned().expect("recv CQ is set");
+        let mut max_inline_data = self.max_inline_data.unwrap_or(cap.max_inline_data);
+        let mut first_err = None;
+        let (qp, qp_init_attr) = loop{
+            let mut qp_init_attr = IbvQpInitAttr::new(
+                &send_cq,
+                &recv_cq,
+                cap.max_send_wr,
+                cap.max_recv_wr,
+                cap.max_send_sge,
+                cap.max_recv_sge,
+                max_inline_data,
+                self.qp_type,
+                self.sq_sig_all as i32,
+            );
+            if let Some(srq) = self.srq{
+                qp_init_attr.set_srq(srq);
+            }
+            info!("qp_init_attr created");
+            let qp = unsafe{ ibv_create_qp(self.pd.as_ptr(), qp_init_attr.as_ptr_mut()) };
+            if !qp.is_null() {
+                break (qp, qp_init_attr);
+            }
+            let err = IbvError::last_os_error("ibv_create_qp").with_device(device_name(context));
+            // The device does not report its inline limit, and providers
+            // reject sizes above it with EINVAL, so ask for less until one
+            // is accepted. Other errors are not about the inline size.
+            if max_inline_data == 0 || err.errno() != Some(libc::EINVAL) {
+                return Err(first_err.unwrap_or(err));
+            }
+            max_inline_data /= 2;
+            info!("{}, retrying with max_inline_data {}", err, max_inline_data);
+            first_err.get_or_insert(err);
+        };
+        info!("qp created: {}", unsafe {
+            (*qp).qp_num
+        });
+        let inner = Box::new(qp);
+        let psn = rand::random::<u32>() & 0xffffff;
+        Ok(IbvQp{
+            inner,
+            pd: self.pd.clone(),
+            recv_cq,
+            send_cq,
+            srq: self.srq.cloned(),
+            cap: qp_init_attr.cap(),
+            qp_type: self.qp_type,
+            access,
+            psn,
+            gidx: self.gidx,
+            port: self.port,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IbvQpType{
     Rc,
     Uc,
@@ -288,92 +632,465 @@ impl IbvQpType{
     }
 }
 
+/// A completion queue. Clones share the same CQ, which is destroyed once
+/// the last clone (including those held by QPs) is dropped.
 #[derive(Clone)]
 pub struct IbvCq{
-    inner: Box<*mut ibv_cq>,
+    inner: Arc<CqHandle>,
+}
+
+struct CqHandle{
+    cq: *mut ibv_cq,
+    // Keeps the channel alive for as long as the CQ is attached to it.
+    channel: Option<IbvCompChannel>,
+    // Events taken from the channel but not yet acknowledged.
+    unacked_events: AtomicU32,
+    mode: Mutex<CompletionMode>,
+    pending_wrs: remote::WrDemux,
 }
 
+/// How `IbvCq::wait` waits for completions. All modes return the same
+/// completions; they only differ in latency and CPU use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompletionMode{
+    /// Busy-poll the CQ, never sleeping. Lowest latency, burns a core.
+    Spin,
+    /// Arm the CQ and sleep on its completion channel.
+    #[default]
+    Event,
+    /// Busy-poll for up to the given time, then arm and sleep.
+    SpinThenSleep(Duration),
+}
+
+impl CqHandle{
+    fn ack_events(&self){
+        let unacked = self.unacked_events.swap(0, Ordering::AcqRel);
+        if unacked > 0 {
+            unsafe{ ibv_ack_cq_events(self.cq, unacked) };
+        }
+    }
+}
+
+impl Drop for CqHandle{
+    fn drop(&mut self){
+        if let Some(channel) = &self.channel{
+            channel.unregister(self.cq);
+        }
+        event::unregister_object_callbacks(self.cq as usize);
+        // ibv_destroy_cq waits for all events to be acknowledged.
+        self.ack_events();
+        unsafe{ ibv_destroy_cq(self.cq) };
+    }
+}
+
+unsafe impl Send for CqHandle{}
+unsafe impl Sync for CqHandle{}
+
 impl IbvCq{
-    pub fn new(context: &IbvContext, cqe: i32, channel: &IbvCompChannel, comp_vector: i32) -> Self{
-        let cq = unsafe{ ibv_create_cq(context.as_ptr(), cqe, null_mut(), channel.as_ptr(), comp_vector) };
-        let inner = Box::new(cq);
-        IbvCq{
-            inner,
+    pub fn new(context: &IbvContext, cqe: i32, channel: &IbvCompChannel, comp_vector: i32) -> Result<Self>{
+        IbvCq::create(context.as_ptr(), cqe, Some(channel), comp_vector)
+    }
+    /// Creates a CQ without a completion channel, which can only be waited
+    /// on in `CompletionMode::Spin`.
+    pub fn new_polled(context: &IbvContext, cqe: i32, comp_vector: i32) -> Result<Self>{
+        let cq = IbvCq::create(context.as_ptr(), cqe, None, comp_vector)?;
+        cq.set_completion_mode(CompletionMode::Spin);
+        Ok(cq)
+    }
+    fn create(context: *mut ibv_context, cqe: i32, channel: Option<&IbvCompChannel>, comp_vector: i32) -> Result<Self>{
+        let channel_ptr = channel.map_or(null_mut(), IbvCompChannel::as_ptr);
+        let cq = unsafe{ ibv_create_cq(context, cqe, null_mut(), channel_ptr, comp_vector) };
+        if cq.is_null() {
+            return Err(IbvError::last_os_error("ibv_create_cq").with_device(device_name(context)));
+        }
+        let inner = Arc::new(CqHandle{
+            cq,
+            channel: channel.cloned(),
+            unacked_events: AtomicU32::new(0),
+            mode: Mutex::new(CompletionMode::default()),
+            pending_wrs: remote::WrDemux::default(),
+        });
+        if let Some(channel) = channel{
+            channel.register(cq, Arc::downgrade(&inner));
         }
+        Ok(IbvCq{
+            inner,
+        })
     }
     pub fn as_ptr(&self) -> *mut ibv_cq {
-        *self.inner
+        self.inner.cq
     }
     pub fn as_ptr_mut(&self) -> &mut ibv_cq{
         unsafe{ &mut *self.as_ptr() }
     }
+    pub fn channel(&self) -> Option<&IbvCompChannel>{
+        self.inner.channel.as_ref()
+    }
+    /// Calls `callback` for `CqError` events of this CQ.
+    pub fn on_async_event<F: Fn(&AsyncEvent) + Send + Sync + 'static>(&self, callback: F){
+        event::register_object_callback(self.as_ptr() as usize, Arc::new(callback));
+    }
+    pub(crate) fn pending_wrs(&self) -> &remote::WrDemux{
+        &self.inner.pending_wrs
+    }
+    pub fn completion_mode(&self) -> CompletionMode{
+        *self.inner.mode.lock().unwrap()
+    }
+    /// Changes how `wait` waits, for all clones of this CQ.
+    pub fn set_completion_mode(&self, mode: CompletionMode){
+        *self.inner.mode.lock().unwrap() = mode;
+    }
+    /// Polls up to `wcs.len()` completions without blocking and returns how
+    /// many were written to the front of `wcs`. Completions of WRs with a
+    /// `PendingWr` are passed on to it instead.
+    pub fn poll(&self, wcs: &mut [WorkCompletion]) -> Result<usize>{
+        self.pending_wrs().poll(self, wcs)
+    }
+    pub(crate) fn poll_raw(&self, wcs: &mut [WorkCompletion]) -> Result<usize>{
+        let num_entries = wcs.len().min(i32::MAX as usize) as i32;
+        let ret = unsafe{ ibv_poll_cq(self.as_ptr(), num_entries, wcs.as_mut_ptr() as *mut ibv_wc) };
+        if ret < 0 {
+            return Err(self.error("ibv_poll_cq", ret));
+        }
+        Ok(ret as usize)
+    }
+    /// Arms the CQ so the next completion (or next solicited one) generates
+    /// an event on its channel.
+    pub fn req_notify(&self, solicited_only: bool) -> Result<()>{
+        let ret = unsafe{ ibv_req_notify_cq(self.as_ptr(), solicited_only as i32) };
+        if ret != 0 {
+            return Err(self.error("ibv_req_notify_cq", ret));
+        }
+        Ok(())
+    }
+    /// Acknowledges all events received for this CQ so far. Events are
+    /// otherwise acknowledged in batches of `CQ_EVENT_ACK_BATCH`.
+    pub fn ack_events(&self){
+        self.inner.ack_events();
+    }
+    fn event_received(&self){
+        let unacked = self.inner.unacked_events.fetch_add(1, Ordering::AcqRel) + 1;
+        if unacked >= CQ_EVENT_ACK_BATCH {
+            self.inner.ack_events();
+        }
+    }
+    /// Waits until at least one completion is available and drains as many
+    /// as fit into `wcs`, returning how many were written. Returns 0 if
+    /// `timeout` passes first; `None` blocks until a completion arrives.
+    ///
+    /// How it waits depends on the `CompletionMode`. When sleeping, the CQ
+    /// is re-armed before every sleep and polled once more after arming, so
+    /// completions racing with the notification are not missed. The channel
+    /// should not be shared with CQs waited on by other threads, as an event
+    /// is consumed by whoever reads it. Threads waiting on the same CQ, here
+    /// or in `PendingWr::wait`, take turns polling it instead.
+    pub fn wait(&self, wcs: &mut [WorkCompletion], timeout: Option<Duration>) -> Result<usize>{
+        self.pending_wrs().wait_untracked(self, wcs, timeout)
+    }
+    // Calls `poll` until it returns true, waiting as `wait` describes in
+    // between. Returns false if `timeout` passes first.
+    pub(crate) fn wait_until(&self, timeout: Option<Duration>, mut poll: impl FnMut() -> Result<bool>) -> Result<bool>{
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mode = self.completion_mode();
+        let spin_until = match mode{
+            CompletionMode::Spin => deadline,
+            CompletionMode::Event => Some(Instant::now()),
+            CompletionMode::SpinThenSleep(budget) => {
+                let spin_until = Instant::now() + budget;
+                Some(deadline.map_or(spin_until, |deadline| deadline.min(spin_until)))
+            },
+        };
+        loop{
+            if poll()? {
+                return Ok(true);
+            }
+            if spin_until.is_some_and(|spin_until| Instant::now() >= spin_until) {
+                break;
+            }
+            std::hint::spin_loop();
+        }
+        if mode == CompletionMode::Spin {
+            return Ok(false);
+        }
+        let channel = match self.channel(){
+            Some(channel) => channel,
+            None => return Err(self.error("ibv_get_cq_event", libc::EINVAL)),
+        };
+        loop{
+            if poll()? {
+                return Ok(true);
+            }
+            self.req_notify(false)?;
+            if poll()? {
+                return Ok(true);
+            }
+            let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if remaining == Some(Duration::ZERO) {
+                return Ok(false);
+            }
+            if channel.get_cq_event(remaining)?.is_none() {
+                return Ok(false);
+            }
+        }
+    }
+    /// Number of entries the CQ was actually created with.
+    pub fn cqe(&self) -> i32{
+        unsafe{ (*self.as_ptr()).cqe }
+    }
+    fn error(&self, verb: &'static str, ret: i32) -> IbvError{
+        let context = unsafe{ (*self.as_ptr()).context };
+        IbvError::from_ret(verb, ret).with_device(device_name(context))
+    }
 }
 
-impl Drop for IbvCq{
-    fn drop(&mut self){
-        unsafe{ ibv_destroy_cq(self.as_ptr()) };
+impl PartialEq for IbvCq{
+    fn eq(&self, other: &Self) -> bool{
+        self.as_ptr() == other.as_ptr()
     }
 }
 
-unsafe impl Send for IbvCq{}
-unsafe impl Sync for IbvCq{}
+impl Eq for IbvCq{}
 
+/// A completion channel. Clones share the same channel.
+#[derive(Clone)]
 pub struct IbvCompChannel{
-    inner: Box<*mut ibv_comp_channel>,
+    inner: Arc<CompChannelHandle>,
+}
+
+struct CompChannelHandle{
+    channel: *mut ibv_comp_channel,
+    // CQs attached to this channel, to map an event back to its IbvCq.
+    cqs: Mutex<HashMap<usize, Weak<CqHandle>>>,
+}
+
+impl Drop for CompChannelHandle{
+    fn drop(&mut self){
+        info!("Destroying comp channel");
+        unsafe{ ibv_destroy_comp_channel(self.channel) };
+    }
 }
 
+unsafe impl Send for CompChannelHandle{}
+unsafe impl Sync for CompChannelHandle{}
+
 impl IbvCompChannel{
-    pub fn new(context: &IbvContext) -> Self{
-        let channel = unsafe{ ibv_create_comp_channel(context.as_ptr()) };
-        let inner = Box::new(channel);
-        IbvCompChannel{
-            inner,
+    pub fn new(context: &IbvContext) -> Result<Self>{
+        IbvCompChannel::from_raw_context(context.as_ptr())
+    }
+    fn from_raw_context(context: *mut ibv_context) -> Result<Self>{
+        let channel = unsafe{ ibv_create_comp_channel(context) };
+        if channel.is_null() {
+            return Err(IbvError::last_os_error("ibv_create_comp_channel").with_device(device_name(context)));
         }
+        Ok(IbvCompChannel{
+            inner: Arc::new(CompChannelHandle{
+                channel,
+                cqs: Mutex::new(HashMap::new()),
+            }),
+        })
     }
     pub fn as_ptr(&self) -> *mut ibv_comp_channel {
-        *self.inner
+        self.inner.channel
     }
     pub fn as_ptr_mut(&self) -> &mut ibv_comp_channel{
         unsafe{ &mut *self.as_ptr() }
     }
+    /// The file descriptor that becomes readable when an event is pending.
+    pub fn fd(&self) -> RawFd{
+        unsafe{ (*self.as_ptr()).fd }
+    }
+    fn register(&self, cq: *mut ibv_cq, handle: Weak<CqHandle>){
+        self.inner.cqs.lock().unwrap().insert(cq as usize, handle);
+    }
+    fn unregister(&self, cq: *mut ibv_cq){
+        self.inner.cqs.lock().unwrap().remove(&(cq as usize));
+    }
+    /// Puts the channel fd into non-blocking mode, so it can be registered
+    /// with an event loop. `get_cq_event` keeps working as it polls the fd
+    /// before reading.
+    pub fn set_nonblocking(&self) -> Result<()>{
+        let flags = unsafe{ libc::fcntl(self.fd(), libc::F_GETFL) };
+        if flags < 0 {
+            return Err(IbvError::last_os_error("fcntl"));
+        }
+        if unsafe{ libc::fcntl(self.fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(IbvError::last_os_error("fcntl"));
+        }
+        Ok(())
+    }
+    /// Waits for the next completion event and returns the CQ it belongs
+    /// to, or `None` if `timeout` passed first. The event is acknowledged
+    /// in batches; the CQ must be re-armed to get further events.
+    pub fn get_cq_event(&self, timeout: Option<Duration>) -> Result<Option<IbvCq>>{
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop{
+            let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if !self.wait_readable(remaining)? {
+                return Ok(None);
+            }
+            if let Some(cq) = self.try_get_cq_event()?{
+                return Ok(Some(cq));
+            }
+        }
+    }
+    /// Reads a pending completion event without blocking, if the channel is
+    /// in non-blocking mode. Returns `None` if no event was pending or it
+    /// belonged to a CQ that is being destroyed.
+    pub fn try_get_cq_event(&self) -> Result<Option<IbvCq>>{
+        let mut cq = null_mut();
+        let mut cq_context = null_mut();
+        let ret = unsafe{ ibv_get_cq_event(self.as_ptr(), &mut cq, &mut cq_context) };
+        if ret != 0 {
+            let err = self.error("ibv_get_cq_event", ret);
+            if err.errno() == Some(libc::EAGAIN) {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+        let handle = self.inner.cqs.lock().unwrap().get(&(cq as usize)).and_then(Weak::upgrade);
+        match handle{
+            Some(inner) => {
+                let cq = IbvCq{ inner };
+                cq.event_received();
+                Ok(Some(cq))
+            },
+            None => {
+                // The CQ is being destroyed, which waits for this ack.
+                unsafe{ ibv_ack_cq_events(cq, 1) };
+                Ok(None)
+            },
+        }
+    }
+    // Returns false if the channel did not become readable within `timeout`.
+    fn wait_readable(&self, timeout: Option<Duration>) -> Result<bool>{
+        let mut pollfd = libc::pollfd{
+            fd: self.fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = match timeout{
+            Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        loop{
+            let ret = unsafe{ libc::poll(&mut pollfd, 1, timeout_ms) };
+            if ret >= 0 {
+                return Ok(ret > 0);
+            }
+            let err = IbvError::last_os_error("poll");
+            if err.errno() != Some(libc::EINTR) {
+                return Err(err);
+            }
+        }
+    }
+    fn error(&self, verb: &'static str, ret: i32) -> IbvError{
+        let context = unsafe{ (*self.as_ptr()).context };
+        IbvError::from_ret(verb, ret).with_device(device_name(context))
+    }
 }
 
-impl Drop for IbvCompChannel{
-    fn drop(&mut self){
-        info!("Destroying comp channel");
-        unsafe{ ibv_destroy_comp_channel(self.as_ptr()) };
-    }
+/// A protection domain. Clones share the same PD; QPs and other objects
+/// created on it hold a clone so it is deallocated last.
+#[derive(Clone)]
+pub struct IbvPd{
+    inner: Arc<PdHandle>,
 }
 
-unsafe impl Send for IbvCompChannel{}
-unsafe impl Sync for IbvCompChannel{}
+struct PdHandle(*mut ibv_pd);
 
-pub struct IbvPd{
-    inner: Box<*mut ibv_pd>,
+impl Drop for PdHandle{
+    fn drop(&mut self){
+        info!("Destroying PD");
+        unsafe{ ibv_dealloc_pd(self.0) };
+    }
 }
 
+unsafe impl Send for PdHandle{}
+unsafe impl Sync for PdHandle{}
+
 impl IbvPd{
-    pub fn new(context: &IbvContext) -> Self{
+    pub fn new(context: &IbvContext) -> Result<Self>{
         let pd = unsafe{ ibv_alloc_pd(context.as_ptr()) };
-        let inner = Box::new(pd);
-        IbvPd{
-            inner,
+        if pd.is_null() {
+            return Err(IbvError::last_os_error("ibv_alloc_pd").with_device(context.device_name()));
         }
+        Ok(IbvPd{
+            inner: Arc::new(PdHandle(pd)),
+        })
     }
     pub fn as_ptr(&self) -> *mut ibv_pd {
-        *self.inner
+        self.inner.0
     }
+    pub fn context(&self) -> *mut ibv_context{
+        unsafe{ (*self.as_ptr()).context }
+    }
+}
+
+/// A shared receive queue. Clones share the same SRQ.
+#[derive(Clone)]
+pub struct IbvSrq{
+    inner: Arc<SrqHandle>,
+}
+
+struct SrqHandle{
+    srq: *mut ibv_srq,
+    max_sge: u32,
+    _pd: IbvPd,
 }
 
-impl Drop for IbvPd{
+impl Drop for SrqHandle{
     fn drop(&mut self){
-        info!("Destroying PD");
-        unsafe{ ibv_dealloc_pd(self.as_ptr()) };
+        info!("Destroying SRQ");
+        event::unregister_object_callbacks(self.srq as usize);
+        unsafe{ ibv_destroy_srq(self.srq) };
     }
 }
 
-unsafe impl Send for IbvPd{}
-unsafe impl Sync for IbvPd{}
+unsafe impl Send for SrqHandle{}
+unsafe impl Sync for SrqHandle{}
+
+impl IbvSrq{
+    pub fn new(pd: &IbvPd, max_wr: u32, max_sge: u32) -> Result<Self>{
+        let mut srq_init_attr = unsafe{ std::mem::zeroed::<ibv_srq_init_attr>() };
+        srq_init_attr.attr.max_wr = max_wr;
+        srq_init_attr.attr.max_sge = max_sge;
+        let srq = unsafe{ ibv_create_srq(pd.as_ptr(), &mut srq_init_attr) };
+        if srq.is_null() {
+            return Err(IbvError::last_os_error("ibv_create_srq").with_device(device_name(pd.context())));
+        }
+        Ok(IbvSrq{
+            inner: Arc::new(SrqHandle{
+                srq,
+                // Granted by the provider, which may round up.
+                max_sge: srq_init_attr.attr.max_sge,
+                _pd: pd.clone(),
+            }),
+        })
+    }
+    pub fn as_ptr(&self) -> *mut ibv_srq {
+        self.inner.srq
+    }
+    /// Calls `callback` for `SrqError` and `SrqLimitReached` events of this SRQ.
+    pub fn on_async_event<F: Fn(&AsyncEvent) + Send + Sync + 'static>(&self, callback: F){
+        event::register_object_callback(self.as_ptr() as usize, Arc::new(callback));
+    }
+    pub fn max_sge(&self) -> u32{
+        self.inner.max_sge
+    }
+    pub fn post_recv(&self, recv_wr: IbvRecvWr) -> Result<()>{
+        let context = unsafe{ (*self.as_ptr()).context };
+        if let Err(wr_id) = recv_wr.check_num_sge(self.inner.max_sge){
+            return Err(IbvError::verb("ibv_post_srq_recv", libc::EINVAL).with_device(device_name(context)).with_wr_id(wr_id));
+        }
+        let mut bad_wr: *mut ibv_recv_wr = ptr::null_mut();
+        let ret = unsafe{ ibv_post_srq_recv(self.as_ptr(), recv_wr.as_ptr(), &mut bad_wr) };
+        if ret != 0 {
+            let wr_id = unsafe { bad_wr.as_ref() }.map_or(recv_wr.wr_id(), |wr| wr.wr_id);
+            return Err(IbvError::from_ret("ibv_post_srq_recv", ret).with_device(device_name(context)).with_wr_id(wr_id));
+        }
+        Ok(())
+    }
+}
 
 pub struct IbvGid{
     inner: ibv_gid,
@@ -397,9 +1114,11 @@ pub struct IbvDevice{
 }
 
 impl IbvDevice{
-    pub fn new(look_up_by: LookUpBy) -> anyhow::Result<Self>{
-        let (device, context, gid_table) = device_lookup(look_up_by)?;
-        let context = IbvContext::from_context(context);
+    pub fn new(look_up_by: LookUpBy) -> Result<Self>{
+        let (context, gid_table) = device_lookup(look_up_by)?;
+        // The device struct outlives the freed device list while the
+        // context is open.
+        let device = unsafe{ (*context.as_ptr()).device };
         let inner = Box::new(device);
         Ok(IbvDevice{
             inner,
@@ -410,6 +1129,41 @@ impl IbvDevice{
     pub fn as_ptr(&self) -> *mut ibv_device {
         *self.inner
     }
+    pub fn name(&self) -> String{
+        device::raw_device_name(self.as_ptr())
+    }
+    pub fn context(&self) -> &IbvContext{
+        &self.context
+    }
+    pub fn attr(&self) -> Result<IbvDeviceAttr>{
+        self.context.query_device()
+    }
+    pub fn port_attr(&self, port: u8) -> Result<IbvPortAttr>{
+        self.context.query_port(port)
+    }
+    pub fn numa_node(&self) -> Result<Option<u32>>{
+        self.context.numa_node()
+    }
+    /// Polls the port until it reaches ACTIVE, returning `PortNotActive`
+    /// with the last seen state if it doesn't within `timeout`.
+    pub fn wait_port_active(&self, port: u8, timeout: Duration) -> Result<IbvPortAttr>{
+        let deadline = Instant::now() + timeout;
+        loop {
+            let port_attr = self.port_attr(port)?;
+            if port_attr.is_active() {
+                return Ok(port_attr);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(IbvError::PortNotActive{
+                    device: self.name(),
+                    port,
+                    state: port_attr.state,
+                });
+            }
+            std::thread::sleep(PORT_POLL_INTERVAL.min(deadline - now));
+        }
+    }
     pub fn gid_table(&self) -> &GidTable{
         &self.gid_table
     }
@@ -424,49 +1178,65 @@ pub enum LookUpBy{
     None,
 }
 
-fn device_lookup(look_up_by: LookUpBy) -> anyhow::Result<(*mut ibv_device, *mut ibv_context, GidTable)>{
-    let device_list: *mut *mut ibv_device = unsafe { ibv_get_device_list(null_mut()) };
-    if device_list.is_null() {
-        return Err(anyhow::anyhow!("No device found"));
-    }
-    let mut i = 0;
-    while !device_list.is_null() {
-        let device = unsafe { *device_list.wrapping_add(i) };
-        if device.is_null() {
-            continue;
+impl std::fmt::Display for LookUpBy{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        match self{
+            LookUpBy::Address(addr) => write!(f, "address {}", addr),
+            LookUpBy::Name(name) => write!(f, "name {}", name),
+            LookUpBy::None => write!(f, "any device"),
         }
-        let context = unsafe { ibv_open_device(device) };
-        if context == null_mut() {
+    }
+}
+
+fn device_lookup(look_up_by: LookUpBy) -> Result<(IbvContext, GidTable)>{
+    let device_list = IbvDeviceList::new()?;
+    let mut skipped = None;
+    for device in device_list.iter() {
+        let device_name = device::raw_device_name(device);
+        let matches_name = match look_up_by{
+            LookUpBy::Name(ref name) => name == &device_name,
+            _ => true,
+        };
+        if !matches_name {
             continue;
         }
-        let device_name: &CStr = unsafe { CStr::from_ptr((*device).name.as_ptr()) }; // Convert array to raw pointer
-        let gid_table = get_gid_table(context, device_name.to_str().unwrap())?;
+        // Contexts of devices that don't match are closed when dropped. A
+        // device that can't be opened or queried doesn't stop the search.
+        let found = device::open_device(device)
+            .and_then(|context| Ok((get_gid_table(context.as_ptr(), &device_name)?, context)));
+        let (gid_table, context) = match found{
+            Ok(found) => found,
+            Err(err) => {
+                warn!("Skipping device {}: {}", device_name, err);
+                skipped.get_or_insert(err);
+                continue;
+            },
+        };
         match look_up_by{
             LookUpBy::Address(addr) => {
                 if gid_table.contains(addr){
                     info!("Device found by address");
-                    return Ok((device, context, gid_table));
+                    return Ok((context, gid_table));
                 }
             },
-            LookUpBy::Name(ref name) => {
-
-                let name = name.as_str();
-                if name == device_name.to_str().unwrap(){
-                    info!("Device found by name");
-                    return Ok((device, context, gid_table));
-                }
+            LookUpBy::Name(_) => {
+                info!("Device found by name");
+                return Ok((context, gid_table));
             },
             LookUpBy::None => {
                 info!("Device found by none");
-                return Ok((device, context, gid_table));
+                return Ok((context, gid_table));
             },
         }
-        i += 1;
     }
-    return Err(anyhow::anyhow!("Device not found"));
+    // Only one device can have the name, so report why it was skipped.
+    match (look_up_by, skipped){
+        (LookUpBy::Name(_), Some(err)) => Err(err),
+        (look_up_by, _) => Err(IbvError::DeviceNotFound(look_up_by.to_string())),
+    }
 }
 
-fn get_gid_table(device_ctx: *mut ibv_context, device_name: &str) -> anyhow::Result<GidTable>{
+fn get_gid_table(device_ctx: *mut ibv_context, device_name: &str) -> Result<GidTable>{
     let mut device_attr: ibv_device_attr = unsafe { std::mem::zeroed::<ibv_device_attr>() };
     let mut gid_table = GidTable{
         v4_table: BTreeMap::new(),
@@ -474,19 +1244,18 @@ fn get_gid_table(device_ctx: *mut ibv_context, device_name: &str) -> anyhow::Res
     };
     let ret = unsafe { ibv_query_device(device_ctx, &mut device_attr) };
     if ret != 0 {
-        return Err(anyhow::anyhow!("Failed to query device"));
+        return Err(IbvError::from_ret("ibv_query_device", ret).with_device(device_name));
     }
     let num_ports = device_attr.phys_port_cnt;
     for i in 1..=num_ports {
-        let mut port_attr: ibv_port_attr = unsafe { std::mem::zeroed::<ibv_port_attr>() };
-        let ret = unsafe { ___ibv_query_port(device_ctx, i, &mut port_attr) };
-        if ret != 0 {
-            return Err(anyhow::anyhow!("Failed to query port"));
-        }
+        let port_attr = IbvPortAttr::query(device_ctx, i)?;
         let gid_tbl_len = port_attr.gid_tbl_len;
         for j in 0..gid_tbl_len {
             let mut gid: ibv_gid = unsafe { std::mem::zeroed() };
-            unsafe { ibv_query_gid(device_ctx, i, j, &mut gid) };
+            let ret = unsafe { ibv_query_gid(device_ctx, i, j, &mut gid) };
+            if ret != 0 {
+                return Err(IbvError::from_ret("ibv_query_gid", ret).with_device(device_name).with_port(i));
+            }
             let address = if let Some(gid_v6) = gid_to_ipv6_string(gid){
                 match gid_v6.to_ipv4(){
                     Some(gid_v4) => {
@@ -528,7 +1297,7 @@ fn get_gid_table(device_ctx: *mut ibv_context, device_name: &str) -> anyhow::Res
     Ok(gid_table)
 }
 
-fn read_gid_type(device_name: &str, port: u8, gid_index: i32) -> anyhow::Result<GidType> {
+fn read_gid_type(device_name: &str, port: u8, gid_index: i32) -> Result<GidType> {
     // Construct the file path
     let path = PathBuf::from(format!(
         "/sys/class/infiniband/{}/ports/{}/gid_attrs/types/{}",
@@ -536,7 +1305,7 @@ fn read_gid_type(device_name: &str, port: u8, gid_index: i32) -> anyhow::Result<
     ));
 
     // Read the file contents
-    let gid_type = fs::read_to_string(path).map_err(|_| anyhow::anyhow!("failed to read"))?;
+    let gid_type = fs::read_to_string(&path).map_err(|source| IbvError::Sysfs{ path, source })?;
 
     // Return the contents as a String
     let gid_type = GidType::from_str(gid_type.trim());
@@ -687,12 +1456,15 @@ pub struct IbvContext{
 }
 
 impl IbvContext{
-    pub fn from_device(device: IbvDevice) -> Self{
+    pub fn from_device(device: IbvDevice) -> Result<Self>{
         let context = unsafe{ ibv_open_device(device.as_ptr()) };
+        if context.is_null() {
+            return Err(IbvError::last_os_error("ibv_open_device").with_device(device_name(device.context.as_ptr())));
+        }
         let inner = Box::new(context);
-        IbvContext{
+        Ok(IbvContext{
             inner,
-        }
+        })
     }
     pub fn from_context(context: *mut ibv_context) -> Self{
         let inner = Box::new(context);
@@ -703,6 +1475,42 @@ impl IbvContext{
     pub fn as_ptr(&self) -> *mut ibv_context {
         *self.inner
     }
+    pub fn device_name(&self) -> String{
+        device_name(self.as_ptr())
+    }
+    pub fn query_device(&self) -> Result<IbvDeviceAttr>{
+        IbvDeviceAttr::query(self.as_ptr())
+    }
+    pub fn query_port(&self, port: u8) -> Result<IbvPortAttr>{
+        IbvPortAttr::query(self.as_ptr(), port)
+    }
+    /// The NUMA node of the device, read from sysfs. Buffers on this node
+    /// avoid crossing the socket interconnect, see `HugeBuffer::on_node`.
+    pub fn numa_node(&self) -> Result<Option<u32>>{
+        device::numa_node(&self.device_name())
+    }
+    /// Reads, acknowledges and returns the next async event, or `None` if
+    /// `timeout` passed first. QP, CQ and SRQ events are also passed to the
+    /// callbacks registered on those objects.
+    pub fn get_async_event(&self, timeout: Option<Duration>) -> Result<Option<AsyncEvent>>{
+        event::get_async_event(self.as_ptr(), timeout)
+    }
+    /// Starts a background thread dispatching async events, see
+    /// `AsyncEventDispatcher`.
+    pub fn async_event_dispatcher(&self) -> Result<AsyncEventDispatcher<'_>>{
+        AsyncEventDispatcher::new(self)
+    }
+}
+
+pub(crate) fn device_name(context: *mut ibv_context) -> String{
+    if context.is_null() {
+        return String::new();
+    }
+    let name = unsafe{ ibv_get_device_name((*context).device) };
+    if name.is_null() {
+        return String::new();
+    }
+    unsafe{ CStr::from_ptr(name) }.to_string_lossy().into_owned()
 }
 
 impl Drop for IbvContext{
@@ -715,27 +1523,80 @@ impl Drop for IbvContext{
 unsafe impl Send for IbvContext{}
 unsafe impl Sync for IbvContext{}
 
-pub struct IbvMr{
+/// A registered memory region.
+///
+/// The registration either owns its buffer (`from_vec`, `from_boxed_slice`)
+/// or mutably borrows it for `'a` (`from_slice`), so the memory cannot be
+/// freed or reused while the NIC may still access it. The contents are
+/// reachable through `as_slice` and `Deref`.
+pub struct IbvMr<'a>{
     inner: Box<*mut ibv_mr>,
+    pd: IbvPd,
+    // Keeps an owned buffer alive until the MR is deregistered.
+    _owned: Option<OwnedBuffer>,
+    // Implicit ODP MRs cover the whole address space and have no contents.
+    implicit: bool,
+    _buffer: PhantomData<&'a mut [u8]>,
+}
+
+impl IbvMr<'static>{
+    /// Registers `buf`, which is kept by the MR until it is dropped.
+    pub fn from_vec(pd: &IbvPd, buf: Vec<u8>, access: IbvAccessFlags) -> Result<Self>{
+        IbvMr::from_boxed_slice(pd, buf.into_boxed_slice(), access)
+    }
+    /// Registers `buf`, which is kept by the MR until it is dropped.
+    pub fn from_boxed_slice(pd: &IbvPd, mut buf: Box<[u8]>, access: IbvAccessFlags) -> Result<Self>{
+        IbvMr::register(pd, buf.as_mut_ptr(), buf.len(), access, Some(OwnedBuffer::Heap{ _buf: buf }))
+    }
+    /// Registers `buf`, which is kept by the MR until it is dropped.
+    /// `IbvAccessFlags::HUGETLB` is added if the buffer came from the
+    /// hugetlbfs pool.
+    pub fn from_huge_buffer(pd: &IbvPd, mut buf: HugeBuffer, access: IbvAccessFlags) -> Result<Self>{
+        let access = if buf.is_hugetlb() { access | IbvAccessFlags::HUGETLB } else { access };
+        IbvMr::register(pd, buf.as_mut_ptr(), buf.len(), access, Some(OwnedBuffer::Huge{ _buf: buf }))
+    }
+    /// Registers memory the MR neither owns nor borrows.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be valid for reads and writes of `length` bytes until the
+    /// MR is dropped, and must not be accessed through other references
+    /// while the slice returned by `as_slice` or `as_mut_slice` is in use.
+    pub unsafe fn new(pd: &IbvPd, addr: *mut u8, length: usize, access: IbvAccessFlags) -> Result<Self>{
+        IbvMr::register(pd, addr, length, access, None)
+    }
 }
 
-impl IbvMr{
-    pub fn new(pd: &IbvPd, addr: *mut u8, length: usize, access: i32) -> Self{
+impl<'a> IbvMr<'a>{
+    /// Registers `buf`, which stays borrowed until the MR is dropped.
+    pub fn from_slice(pd: &IbvPd, buf: &'a mut [u8], access: IbvAccessFlags) -> Result<Self>{
+        IbvMr::register(pd, buf.as_mut_ptr(), buf.len(), access, None)
+    }
+    fn register(pd: &IbvPd, addr: *mut u8, length: usize, access: IbvAccessFlags, owned: Option<OwnedBuffer>) -> Result<Self>{
         let addr = addr as *mut std::ffi::c_void;
-        info!("access: {}", access.clone() as i32);
-        let mr = unsafe{ ibv_reg_mr(pd.as_ptr(), addr, length, access) };
+        info!("access: {:?}", access);
+        let mr = unsafe{ ibv_reg_mr(pd.as_ptr(), addr, length, access.bits() as i32) };
+        if mr.is_null() {
+            return Err(IbvError::last_os_error("ibv_reg_mr").with_device(device_name(pd.context())));
+        }
         let mr_addr = unsafe{ (*mr).addr as u64 };
         let mr_rkey = unsafe{ (*mr).rkey };
         let mr_lkey = unsafe{ (*mr).lkey };
         info!("mr addr: {}, rkey: {}, lkey: {}", mr_addr, mr_rkey, mr_lkey);
-        let inner = Box::new(mr);
-        IbvMr{
-            inner,
-        }
+        Ok(IbvMr{
+            inner: Box::new(mr),
+            pd: pd.clone(),
+            _owned: owned,
+            implicit: false,
+            _buffer: PhantomData,
+        })
     }
     pub fn as_ptr(&self) -> *mut ibv_mr{
         *self.inner
     }
+    pub fn pd(&self) -> &IbvPd{
+        &self.pd
+    }
     pub fn addr(&self) -> u64{
         unsafe{ (*self.as_ptr()).addr as u64 }
     }
@@ -748,64 +1609,88 @@ impl IbvMr{
     pub fn rkey(&self) -> u32{
         unsafe{ (*self.as_ptr()).rkey }
     }
-}
-
-impl Drop for IbvMr{
-    fn drop(&mut self){
-        info!("Destroying MR");
-        unsafe{ ibv_dereg_mr(self.as_ptr()) };
+    /// The registered memory. Data written by the NIC is only complete once
+    /// the corresponding work completion has been polled. Empty for an
+    /// implicit ODP MR.
+    pub fn as_slice(&self) -> &[u8]{
+        if self.implicit || self.length() == 0 {
+            return &[];
+        }
+        unsafe{ std::slice::from_raw_parts(self.addr() as *const u8, self.length()) }
+    }
+    pub fn as_mut_slice(&mut self) -> &mut [u8]{
+        if self.implicit || self.length() == 0 {
+            return &mut [];
+        }
+        unsafe{ std::slice::from_raw_parts_mut(self.addr() as *mut u8, self.length()) }
     }
 }
 
-unsafe impl Send for IbvMr{}
-unsafe impl Sync for IbvMr{}
-
-#[derive(Clone)]
-pub enum IbvAccessFlags{
-    LocalWrite = 1,
-    RemoteWrite = 2,
-    RemoteRead = 4,
-    RemoteAtomic = 8,
-    MwBind = 16,
-    ZeroBased = 32,
-    OnDemand = 64,
-    HugeTlb = 128,
+// Only held so the memory is freed after the MR is deregistered.
+enum OwnedBuffer{
+    Heap{ _buf: Box<[u8]> },
+    Huge{ _buf: HugeBuffer },
 }
 
-impl IbvAccessFlags{
-    pub fn as_i32(&self) -> i32{
-        self.clone() as i32
+impl Deref for IbvMr<'_>{
+    type Target = [u8];
+    fn deref(&self) -> &[u8]{
+        self.as_slice()
     }
 }
 
-impl Into <i32> for IbvAccessFlags{
-    fn into(self) -> i32{
-        self as i32
+impl DerefMut for IbvMr<'_>{
+    fn deref_mut(&mut self) -> &mut [u8]{
+        self.as_mut_slice()
     }
 }
 
-impl From <i32> for IbvAccessFlags{
-    fn from(val: i32) -> Self{
-        match val{
-            1 => IbvAccessFlags::LocalWrite,
-            2 => IbvAccessFlags::RemoteWrite,
-            4 => IbvAccessFlags::RemoteRead,
-            8 => IbvAccessFlags::RemoteAtomic,
-            16 => IbvAccessFlags::MwBind,
-            32 => IbvAccessFlags::ZeroBased,
-            64 => IbvAccessFlags::OnDemand,
-            128 => IbvAccessFlags::HugeTlb,
-            _ => IbvAccessFlags::LocalWrite,
+impl Drop for IbvMr<'_>{
+    fn drop(&mut self){
+        info!("Destroying MR");
+        let ret = unsafe{ ibv_dereg_mr(self.as_ptr()) };
+        if ret != 0 {
+            // The device may still access the buffer, e.g. through a bound
+            // memory window, so an owned one is leaked instead of freed.
+            warn!("{}", IbvError::from_ret("ibv_dereg_mr", ret).with_device(device_name(self.pd.context())));
+            std::mem::forget(self._owned.take());
         }
     }
 }
 
-impl BitOr for IbvAccessFlags{
-    type Output = i32;
-    fn bitor(self, rhs: Self) -> Self::Output{
-        self as i32 | rhs as i32
+unsafe impl Send for IbvMr<'_>{}
+unsafe impl Sync for IbvMr<'_>{}
+
+bitflags! {
+    /// Access rights of an MR, MW or QP (`ibv_access_flags`).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct IbvAccessFlags: u32{
+        const LOCAL_WRITE = ibv_access_flags::IBV_ACCESS_LOCAL_WRITE.0;
+        const REMOTE_WRITE = ibv_access_flags::IBV_ACCESS_REMOTE_WRITE.0;
+        const REMOTE_READ = ibv_access_flags::IBV_ACCESS_REMOTE_READ.0;
+        const REMOTE_ATOMIC = ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC.0;
+        const MW_BIND = ibv_access_flags::IBV_ACCESS_MW_BIND.0;
+        const ZERO_BASED = ibv_access_flags::IBV_ACCESS_ZERO_BASED.0;
+        const ON_DEMAND = ibv_access_flags::IBV_ACCESS_ON_DEMAND.0;
+        const HUGETLB = ibv_access_flags::IBV_ACCESS_HUGETLB.0;
+        const FLUSH_GLOBAL = ibv_access_flags::IBV_ACCESS_FLUSH_GLOBAL.0;
+        const FLUSH_PERSISTENT = ibv_access_flags::IBV_ACCESS_FLUSH_PERSISTENT.0;
+        /// Optional, ignored by devices that do not support it.
+        const RELAXED_ORDERING = ibv_access_flags::IBV_ACCESS_RELAXED_ORDERING.0;
     }
 }
+
+impl IbvAccessFlags{
+    /// Local write plus remote read and write, what the sender and receiver
+    /// register their buffers with.
+    pub fn local_and_remote() -> Self{
+        IbvAccessFlags::LOCAL_WRITE | IbvAccessFlags::REMOTE_WRITE | IbvAccessFlags::REMOTE_READ
+    }
+}
+
+/// A scatter/gather element, one contiguous piece of registered memory.
+#[repr(transparent)]
+#[derive(Clone, Copy)]
 pub struct IbvSge{
     inner: ibv_sge,
 }
@@ -821,6 +1706,22 @@ impl IbvSge{
             inner: sge
         }
     }
+    /// An SGE covering `range` of `mr`, relative to its start.
+    pub fn from_mr(mr: &IbvMr, range: Range<usize>) -> Result<Self>{
+        if range.start > range.end || (!mr.is_implicit() && range.end > mr.length()) || range.len() > u32::MAX as usize {
+            return Err(IbvError::invalid_argument("IbvSge::from_mr", format!("range {:?} is outside the MR of {} bytes or longer than 4 GiB", range, mr.length())));
+        }
+        Ok(IbvSge::new(mr.addr() + range.start as u64, range.len() as u32, mr.lkey()))
+    }
+    pub fn addr(&self) -> u64{
+        self.inner.addr
+    }
+    pub fn length(&self) -> u32{
+        self.inner.length
+    }
+    pub fn lkey(&self) -> u32{
+        self.inner.lkey
+    }
     pub fn as_ptr(&self) -> *mut ibv_sge{
         &self.inner as *const _ as *mut _
     }
@@ -829,23 +1730,42 @@ impl IbvSge{
 unsafe impl Send for IbvSge{}
 unsafe impl Sync for IbvSge{}
 
+fn sges_from_mrs(segments: &[(&IbvMr, Range<usize>)]) -> Result<Vec<IbvSge>>{
+    segments.iter().map(|(mr, range)| IbvSge::from_mr(mr, range.clone())).collect()
+}
+
+// Points the WR at the SGEs, whose heap buffer stays put when the WR moves.
+fn sg_list_ptr(sg_list: &mut [IbvSge]) -> *mut ibv_sge{
+    if sg_list.is_empty() {
+        return ptr::null_mut();
+    }
+    sg_list.as_mut_ptr() as *mut ibv_sge
+}
+
+/// A receive WR. It owns its SGEs, so they stay valid until it is posted.
 pub struct IbvRecvWr{
     inner: ibv_recv_wr,
+    sg_list: Vec<IbvSge>,
     next: Option<Box<IbvRecvWr>>,
 }
 
 impl IbvRecvWr{
-    pub fn new(id: u64, sg_list: IbvSge, num_sge: i32) -> Self{
+    pub fn new(id: u64, mut sg_list: Vec<IbvSge>) -> Self{
         let mut wr: ibv_recv_wr = unsafe { std::mem::zeroed() };  // Create a zeroed ibv_recv_wr
         wr.wr_id = id;
-        wr.sg_list = sg_list.as_ptr();
-        wr.num_sge = num_sge;
+        wr.sg_list = sg_list_ptr(&mut sg_list);
+        wr.num_sge = sg_list.len() as i32;
 
         IbvRecvWr{
             inner: wr,
+            sg_list,
             next: None,
         }
     }
+    /// A receive WR scattering into the given ranges of registered memory.
+    pub fn from_mrs(id: u64, segments: &[(&IbvMr, Range<usize>)]) -> Result<Self>{
+        Ok(IbvRecvWr::new(id, sges_from_mrs(segments)?))
+    }
 
     pub fn set_next(&mut self, next: IbvRecvWr){
         self.next = Some(Box::new(next));
@@ -854,6 +1774,27 @@ impl IbvRecvWr{
         }
     }
 
+    pub fn wr_id(&self) -> u64{
+        self.inner.wr_id
+    }
+
+    pub fn sg_list(&self) -> &[IbvSge]{
+        &self.sg_list
+    }
+
+    // Returns the wr_id of the first WR in the chain with more than
+    // `max_sge` SGEs.
+    pub(crate) fn check_num_sge(&self, max_sge: u32) -> std::result::Result<(), u64>{
+        let mut wr = Some(self);
+        while let Some(current) = wr{
+            if current.sg_list.len() > max_sge as usize {
+                return Err(current.wr_id());
+            }
+            wr = current.next.as_deref();
+        }
+        Ok(())
+    }
+
     pub fn as_ptr(&self) -> *mut ibv_recv_wr {
         &self.inner as *const _ as *mut _
     }
@@ -862,34 +1803,103 @@ impl IbvRecvWr{
 unsafe impl Send for IbvRecvWr{}
 unsafe impl Sync for IbvRecvWr{}
 
+/// A send WR. It owns its SGEs, so they stay valid until it is posted.
 pub struct IbvSendWr{
     inner: ibv_send_wr,
+    sg_list: Vec<IbvSge>,
     next: Option<Box<IbvSendWr>>,
 }
 
 impl IbvSendWr{
     pub fn new(id: u64,
-        sg_list: IbvSge,
-        num_sge: i32,
+        mut sg_list: Vec<IbvSge>,
         opcode: IbvWrOpcode,
-        send_flags: i32,
+        send_flags: IbvSendFlags,
         remote_addr: u64,
         rkey: u32,
     ) -> Self{
         let mut wr: ibv_send_wr = unsafe { std::mem::zeroed() };  // Create a zeroed ibv_send_wr
         wr.wr_id = id;
-        wr.sg_list = sg_list.as_ptr();
-        wr.num_sge = num_sge;
+        wr.sg_list = sg_list_ptr(&mut sg_list);
+        wr.num_sge = sg_list.len() as i32;
         wr.opcode = opcode.get();
-        wr.send_flags = send_flags as u32;
+        wr.send_flags = send_flags.bits();
         wr.wr.rdma.remote_addr = remote_addr;
         wr.wr.rdma.rkey = rkey;
 
         IbvSendWr {
             inner: wr,
+            sg_list,
+            next: None,
+        }
+    }
+    /// A send WR gathering from the given ranges of registered memory.
+    pub fn from_mrs(id: u64,
+        segments: &[(&IbvMr, Range<usize>)],
+        opcode: IbvWrOpcode,
+        send_flags: IbvSendFlags,
+        remote_addr: u64,
+        rkey: u32,
+    ) -> Result<Self>{
+        Ok(IbvSendWr::new(id, sges_from_mrs(segments)?, opcode, send_flags, remote_addr, rkey))
+    }
+
+    /// A LOCAL_INV WR invalidating `rkey`, typically of a type 2 memory
+    /// window, on the local side.
+    pub fn local_inv(id: u64, rkey: u32) -> Self{
+        let mut wr: ibv_send_wr = unsafe { std::mem::zeroed() };
+        wr.wr_id = id;
+        wr.opcode = IbvWrOpcode::LocalInv.get();
+        wr.imm_data_invalidated_rkey_union.invalidated_rkey = rkey;
+        IbvSendWr::from_raw(wr)
+    }
+    /// An ATOMIC_CMP_AND_SWP WR replacing the 8 bytes at `remote_addr` with
+    /// `swap` if they equal `compare`. The prior value is written to `result`,
+    /// which has to be 8 bytes long.
+    pub fn compare_and_swap(id: u64, result: IbvSge, remote_addr: u64, rkey: u32, compare: u64, swap: u64) -> Self{
+        let mut wr = IbvSendWr::new(id, vec![result], IbvWrOpcode::AtomicCmpAndSwp, IbvSendFlags::SIGNALED, 0, 0);
+        wr.inner.wr.atomic = atomic_t{
+            remote_addr,
+            compare_add: compare,
+            swap,
+            rkey,
+        };
+        wr
+    }
+    /// An ATOMIC_FETCH_AND_ADD WR adding `add` to the 8 bytes at
+    /// `remote_addr`. The prior value is written to `result`.
+    pub fn fetch_add(id: u64, result: IbvSge, remote_addr: u64, rkey: u32, add: u64) -> Self{
+        let mut wr = IbvSendWr::new(id, vec![result], IbvWrOpcode::AtomicFetchAndAdd, IbvSendFlags::SIGNALED, 0, 0);
+        wr.inner.wr.atomic = atomic_t{
+            remote_addr,
+            compare_add: add,
+            swap: 0,
+            rkey,
+        };
+        wr
+    }
+    pub(crate) fn from_raw(wr: ibv_send_wr) -> Self{
+        IbvSendWr{
+            inner: wr,
+            sg_list: Vec::new(),
             next: None,
         }
     }
+    /// Sets the remote rkey invalidated by a `SendWithInv` WR.
+    pub fn set_invalidate_rkey(&mut self, rkey: u32){
+        self.inner.imm_data_invalidated_rkey_union.invalidated_rkey = rkey;
+    }
+    /// Sets the immediate data, in host byte order, carried by a
+    /// `SendWithImm` or `RdmaWriteWithImm` WR. The peer reads it from the
+    /// receive completion with `WorkCompletion::imm_data`.
+    pub fn set_imm_data(&mut self, imm_data: u32){
+        self.inner.imm_data_invalidated_rkey_union.imm_data = imm_data.to_be();
+    }
+    /// Like `set_imm_data`, for building a WR in one expression.
+    pub fn with_imm_data(mut self, imm_data: u32) -> Self{
+        self.set_imm_data(imm_data);
+        self
+    }
 
     pub fn set_next(&mut self, next: IbvSendWr){
         self.next = Some(Box::new(next));
@@ -898,6 +1908,31 @@ impl IbvSendWr{
         }
     }
 
+    pub fn wr_id(&self) -> u64{
+        self.inner.wr_id
+    }
+
+    pub fn sg_list(&self) -> &[IbvSge]{
+        &self.sg_list
+    }
+
+    // See `IbvRecvWr::check_num_sge`.
+    pub(crate) fn check_num_sge(&self, max_sge: u32) -> std::result::Result<(), u64>{
+        let mut wr = Some(self);
+        while let Some(current) = wr{
+            if current.sg_list.len() > max_sge as usize {
+                return Err(current.wr_id());
+            }
+            wr = current.next.as_deref();
+        }
+        Ok(())
+    }
+
+    /// Requests a completion for this WR even if the QP does not signal all sends.
+    pub fn set_signaled(&mut self){
+        self.inner.send_flags |= IbvSendFlags::SIGNALED.bits();
+    }
+
     pub fn as_ptr(&self) -> *mut ibv_send_wr {
         &self.inner as *const _ as *mut _
     }
@@ -942,23 +1977,15 @@ impl IbvWrOpcode{
     }
 }
 
-pub enum IbvSendFlags{
-    Fence,
-    Signaled,
-    Solicited,
-    Inline,
-    IpCsum,
-}
-
-impl IbvSendFlags{
-    pub fn get(&self) -> u32{
-        match self{
-            IbvSendFlags::Fence => ibv_send_flags::IBV_SEND_FENCE.0,
-            IbvSendFlags::Signaled => ibv_send_flags::IBV_SEND_SIGNALED.0,
-            IbvSendFlags::Solicited => ibv_send_flags::IBV_SEND_SOLICITED.0,
-            IbvSendFlags::Inline => ibv_send_flags::IBV_SEND_INLINE.0,
-            IbvSendFlags::IpCsum => ibv_send_flags::IBV_SEND_IP_CSUM.0,
-        }
+bitflags! {
+    /// Flags of a send WR (`ibv_send_flags`).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct IbvSendFlags: u32{
+        const FENCE = ibv_send_flags::IBV_SEND_FENCE.0;
+        const SIGNALED = ibv_send_flags::IBV_SEND_SIGNALED.0;
+        const SOLICITED = ibv_send_flags::IBV_SEND_SOLICITED.0;
+        const INLINE = ibv_send_flags::IBV_SEND_INLINE.0;
+        const IP_CSUM = ibv_send_flags::IBV_SEND_IP_CSUM.0;
     }
 }
 
@@ -982,17 +2009,86 @@ pub struct MrMetadata{
 
 impl MrMetadata{
     pub const SIZE: usize = std::mem::size_of::<MrMetadata>();
-    pub fn addr(&self) -> *mut u8{
-        &self as *const _ as *mut u8
-    }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct QpMetadata{
     pub subnet_id: u64,
     pub interface_id: u64,
     pub psn: u32,
     pub qpn: u32,
+    /// Parameters the sending side wants to connect with.
+    pub params: ConnectParams,
+}
+
+/// Attributes applied on the RTR and RTS transitions.
+///
+/// The defaults match what `connect` used to hard-code. Both sides exchange
+/// their parameters in `QpMetadata` and connect with the result of
+/// `negotiate`, so they agree on the path MTU and read depth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectParams{
+    pub path_mtu: Mtu,
+    /// Encoded RNR NAK delay advertised to the peer (0-31, 12 = 0.64 ms).
+    pub min_rnr_timer: u8,
+    /// Local ACK timeout as 4.096 us * 2^timeout (0 = infinite).
+    pub timeout: u8,
+    pub retry_cnt: u8,
+    /// RNR retries, 7 means retry forever.
+    pub rnr_retry: u8,
+    pub hop_limit: u8,
+    pub sl: u8,
+    /// Traffic class of the GRH, carrying the DSCP in its upper six bits on RoCE.
+    pub traffic_class: u8,
+    /// Outstanding RDMA reads and atomics this side initiates.
+    pub max_rd_atomic: u8,
+    /// Outstanding RDMA reads and atomics this side accepts as responder.
+    pub max_dest_rd_atomic: u8,
+}
+
+impl Default for ConnectParams{
+    fn default() -> Self{
+        ConnectParams{
+            path_mtu: Mtu::Mtu4096,
+            min_rnr_timer: 12,
+            timeout: 14,
+            retry_cnt: 7,
+            rnr_retry: 7,
+            hop_limit: 10,
+            sl: 0,
+            traffic_class: 0,
+            max_rd_atomic: 1,
+            max_dest_rd_atomic: 1,
+        }
+    }
+}
+
+impl ConnectParams{
+    /// Sets the traffic class from a DSCP value, leaving ECN bits cleared.
+    pub fn with_dscp(mut self, dscp: u8) -> Self{
+        self.traffic_class = (dscp & 0x3f) << 2;
+        self
+    }
+    /// Combines the local parameters with the ones received from the peer.
+    /// The path MTU is the smaller of both, and no more reads are initiated
+    /// than the peer accepts. All other values are local decisions.
+    pub fn negotiate(&self, remote: &ConnectParams) -> ConnectParams{
+        ConnectParams{
+            path_mtu: self.path_mtu.min(remote.path_mtu),
+            max_rd_atomic: self.max_rd_atomic.min(remote.max_dest_rd_atomic),
+            ..*self
+        }
+    }
+    /// Lowers the MTU to what the port runs at and the read depth to what
+    /// the device supports. The MTU is kept if the port reports none.
+    pub fn clamp_to(&self, device_attr: &IbvDeviceAttr, port_attr: &IbvPortAttr) -> ConnectParams{
+        ConnectParams{
+            path_mtu: port_attr.active_mtu.map_or(self.path_mtu, |mtu| self.path_mtu.min(mtu)),
+            max_rd_atomic: self.max_rd_atomic.min(device_attr.max_qp_init_rd_atom.min(u8::MAX as u32) as u8),
+            max_dest_rd_atomic: self.max_dest_rd_atomic.min(device_attr.max_qp_rd_atom.min(u8::MAX as u32) as u8),
+            ..*self
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -1005,5 +2101,87 @@ pub enum SocketCommCommand{
     Mr(MrMetadata),
     InitQp(u32, Family),
     ConnectQp(QpMetadata),
+    /// Index and new PSN of a QP moved back to INIT by recovery.
+    RecoverQp(u32, u32),
     Stop,
 }
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn device_attr(max_qp_init_rd_atom: i32, max_qp_rd_atom: i32) -> IbvDeviceAttr{
+        let mut attr = unsafe{ std::mem::zeroed::<ibv_device_attr_ex>() };
+        attr.orig_attr.max_qp_init_rd_atom = max_qp_init_rd_atom;
+        attr.orig_attr.max_qp_rd_atom = max_qp_rd_atom;
+        IbvDeviceAttr::from_raw(&attr)
+    }
+
+    fn port_attr(active_mtu: ibv_mtu::Type) -> IbvPortAttr{
+        let mut attr = unsafe{ std::mem::zeroed::<ibv_port_attr>() };
+        attr.active_mtu = active_mtu;
+        IbvPortAttr::from_raw(1, &attr)
+    }
+
+    #[test]
+    fn negotiate_takes_smaller_mtu_and_peer_read_depth(){
+        let local = ConnectParams{
+            path_mtu: Mtu::Mtu4096,
+            max_rd_atomic: 16,
+            max_dest_rd_atomic: 8,
+            ..ConnectParams::default()
+        };
+        let remote = ConnectParams{
+            path_mtu: Mtu::Mtu1024,
+            max_rd_atomic: 2,
+            max_dest_rd_atomic: 4,
+            timeout: 20,
+            ..ConnectParams::default()
+        };
+        let params = local.negotiate(&remote);
+        assert_eq!(params.path_mtu, Mtu::Mtu1024);
+        assert_eq!(params.max_rd_atomic, 4);
+        assert_eq!(params.max_dest_rd_atomic, 8);
+        assert_eq!(params.timeout, local.timeout);
+    }
+
+    #[test]
+    fn negotiate_is_symmetric_for_mtu(){
+        let a = ConnectParams{ path_mtu: Mtu::Mtu2048, ..ConnectParams::default() };
+        let b = ConnectParams{ path_mtu: Mtu::Mtu512, ..ConnectParams::default() };
+        assert_eq!(a.negotiate(&b).path_mtu, b.negotiate(&a).path_mtu);
+    }
+
+    #[test]
+    fn clamp_to_lowers_to_device_and_port_limits(){
+        let params = ConnectParams{
+            path_mtu: Mtu::Mtu4096,
+            max_rd_atomic: 16,
+            max_dest_rd_atomic: 16,
+            ..ConnectParams::default()
+        };
+        let clamped = params.clamp_to(&device_attr(4, 8), &port_attr(ibv_mtu::IBV_MTU_2048));
+        assert_eq!(clamped.path_mtu, Mtu::Mtu2048);
+        assert_eq!(clamped.max_rd_atomic, 4);
+        assert_eq!(clamped.max_dest_rd_atomic, 8);
+    }
+
+    #[test]
+    fn clamp_to_never_raises(){
+        let params = ConnectParams{
+            path_mtu: Mtu::Mtu1024,
+            max_rd_atomic: 1,
+            max_dest_rd_atomic: 1,
+            ..ConnectParams::default()
+        };
+        let clamped = params.clamp_to(&device_attr(1000, 1000), &port_attr(ibv_mtu::IBV_MTU_4096));
+        assert_eq!(clamped, params);
+    }
+
+    #[test]
+    fn clamp_to_keeps_mtu_for_unknown_port_mtu(){
+        let params = ConnectParams::default();
+        let clamped = params.clamp_to(&device_attr(1, 1), &port_attr(0));
+        assert_eq!(clamped.path_mtu, params.path_mtu);
+    }
+}
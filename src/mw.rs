@@ -0,0 +1,185 @@
+use std::{marker::PhantomData, ops::Range};
+use log::info;
+use rdma_sys::*;
+
+use crate::{device_name, IbvAccessFlags, IbvDeviceAttr, IbvError, IbvMr, IbvPd, IbvSendFlags, IbvSendWr, IbvWrOpcode, Qp, Result, Rts};
+
+/// The kind of a memory window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MwType{
+    /// Bound with `ibv_bind_mw` and invalidated by binding it again with a
+    /// zero length.
+    Type1,
+    /// Bound with a `BindMw` send WR on one QP and invalidated with
+    /// `LocalInv` or by the peer with `SendWithInv`.
+    Type2,
+}
+
+impl MwType{
+    pub fn get(&self) -> ibv_mw_type::Type{
+        match self{
+            MwType::Type1 => ibv_mw_type::IBV_MW_TYPE_1,
+            MwType::Type2 => ibv_mw_type::IBV_MW_TYPE_2,
+        }
+    }
+}
+
+/// A memory window granting remote access to a sub-range of an MR under
+/// its own rkey, which can be revoked without deregistering the MR.
+///
+/// The MR has to be registered with `IbvAccessFlags::MW_BIND`. Every bind
+/// produces a new rkey, so a peer holding an old one loses access. MRs the
+/// window is bound to stay borrowed until it is dropped, as they cannot be
+/// deregistered while bound.
+pub struct IbvMw<'m>{
+    inner: Box<*mut ibv_mw>,
+    pd: IbvPd,
+    mw_type: MwType,
+    rkey: u32,
+    _mr: PhantomData<&'m IbvMr<'m>>,
+}
+
+impl<'m> IbvMw<'m>{
+    pub fn new(pd: &IbvPd, mw_type: MwType) -> Result<Self>{
+        let context = pd.context();
+        let attr = IbvDeviceAttr::query(context)?;
+        let cap = match mw_type{
+            MwType::Type1 => ibv_device_cap_flags::IBV_DEVICE_MEM_WINDOW.0,
+            MwType::Type2 => ibv_device_cap_flags::IBV_DEVICE_MEM_WINDOW_TYPE_2A.0 | ibv_device_cap_flags::IBV_DEVICE_MEM_WINDOW_TYPE_2B.0,
+        };
+        if attr.device_cap_flags & cap == 0 || attr.max_mw == 0 {
+            return Err(IbvError::verb("ibv_alloc_mw", libc::EOPNOTSUPP).with_device(device_name(context)));
+        }
+        let mw = match unsafe{ ibv_alloc_mw(pd.as_ptr(), mw_type.get()) }{
+            Some(mw) if !mw.is_null() => mw,
+            _ => return Err(IbvError::last_os_error("ibv_alloc_mw").with_device(device_name(context))),
+        };
+        let rkey = unsafe{ (*mw).rkey };
+        info!("Allocated {:?} memory window with rkey {}", mw_type, rkey);
+        Ok(IbvMw{
+            inner: Box::new(mw),
+            pd: pd.clone(),
+            mw_type,
+            rkey,
+            _mr: PhantomData,
+        })
+    }
+    pub fn as_ptr(&self) -> *mut ibv_mw{
+        *self.inner
+    }
+    pub fn mw_type(&self) -> MwType{
+        self.mw_type
+    }
+    pub fn pd(&self) -> &IbvPd{
+        &self.pd
+    }
+    /// The rkey of the current binding, to hand to the peer.
+    pub fn rkey(&self) -> u32{
+        self.rkey
+    }
+    /// Binds the window to `range` of `mr`, relative to its start, with
+    /// `access` (remote read/write/atomic). Type 1 windows are bound with
+    /// `ibv_bind_mw`, type 2 windows with a `BindMw` WR posted on `qp`. A
+    /// signaled completion with `wr_id` is generated on the QP's send CQ,
+    /// after which the peer may use the new rkey. Returns the new rkey.
+    pub fn bind(&mut self, qp: &Qp<Rts>, wr_id: u64, mr: &'m IbvMr<'m>, range: Range<u64>, access: IbvAccessFlags) -> Result<u32>{
+        let bind_info = bind_info(mr, range, access)?;
+        match self.mw_type{
+            MwType::Type1 => self.bind_type1(qp, wr_id, bind_info),
+            MwType::Type2 => self.bind_type2(qp, wr_id, bind_info),
+        }
+    }
+    /// Revokes the current binding of a type 1 window by binding it to
+    /// nothing.
+    pub fn unbind(&mut self, qp: &Qp<Rts>, wr_id: u64) -> Result<()>{
+        if self.mw_type != MwType::Type1 {
+            return Err(IbvError::invalid_argument("IbvMw::unbind", "only type 1 windows are unbound, invalidate type 2 windows instead"));
+        }
+        let bind_info = unsafe{ std::mem::zeroed::<ibv_mw_bind_info>() };
+        self.bind_type1(qp, wr_id, bind_info)?;
+        Ok(())
+    }
+    fn bind_type1(&mut self, qp: &Qp<Rts>, wr_id: u64, bind_info: ibv_mw_bind_info) -> Result<u32>{
+        let mut mw_bind = ibv_mw_bind{
+            wr_id,
+            send_flags: IbvSendFlags::SIGNALED.bits(),
+            bind_info,
+        };
+        let ret = unsafe{ ibv_bind_mw(qp.as_ptr(), self.as_ptr(), &mut mw_bind) };
+        if ret != 0 {
+            return Err(IbvError::from_ret("ibv_bind_mw", ret).with_qp(qp.qp_num()).with_wr_id(wr_id));
+        }
+        // The provider stores the rkey of the new binding in the MW.
+        self.rkey = unsafe{ (*self.as_ptr()).rkey };
+        Ok(self.rkey)
+    }
+    fn bind_type2(&mut self, qp: &Qp<Rts>, wr_id: u64, bind_info: ibv_mw_bind_info) -> Result<u32>{
+        let rkey = unsafe{ ibv_inc_rkey(self.rkey) };
+        let mut wr: ibv_send_wr = unsafe{ std::mem::zeroed() };
+        wr.wr_id = wr_id;
+        wr.opcode = IbvWrOpcode::BindMw.get();
+        wr.send_flags = IbvSendFlags::SIGNALED.bits();
+        wr.bind_mw_tso_union.bind_mw = bind_mw_t{
+            mw: self.as_ptr(),
+            rkey,
+            bind_info,
+        };
+        qp.post_send(IbvSendWr::from_raw(wr))?;
+        // The window only takes the new rkey once the WR is accepted.
+        self.rkey = rkey;
+        Ok(rkey)
+    }
+    /// Builds the `LocalInv` WR revoking the current rkey of a type 2
+    /// window.
+    pub fn invalidate_wr(&self, wr_id: u64) -> IbvSendWr{
+        IbvSendWr::local_inv(wr_id, self.rkey)
+    }
+}
+
+fn bind_info(mr: &IbvMr, range: Range<u64>, access: IbvAccessFlags) -> Result<ibv_mw_bind_info>{
+    let (addr, length) = bind_range(mr.addr(), mr.length(), range)?;
+    Ok(ibv_mw_bind_info{
+        mr: mr.as_ptr(),
+        addr,
+        length,
+        mw_access_flags: access.bits(),
+    })
+}
+
+// The address and length of `range` of an MR of `mr_length` bytes at
+// `mr_addr`.
+fn bind_range(mr_addr: u64, mr_length: usize, range: Range<u64>) -> Result<(u64, u64)>{
+    if range.start > range.end || range.end > mr_length as u64 {
+        return Err(IbvError::invalid_argument("IbvMw::bind", format!("range {:?} is outside the MR of {} bytes", range, mr_length)));
+    }
+    Ok((mr_addr + range.start, range.end - range.start))
+}
+
+impl Drop for IbvMw<'_>{
+    fn drop(&mut self){
+        info!("Destroying MW");
+        unsafe{ ibv_dealloc_mw(self.as_ptr()) };
+    }
+}
+
+unsafe impl Send for IbvMw<'_>{}
+unsafe impl Sync for IbvMw<'_>{}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn bind_range_is_relative_to_the_mr(){
+        assert_eq!(bind_range(0x1000, 64, 8..24).unwrap(), (0x1008, 16));
+        assert_eq!(bind_range(0x1000, 64, 0..64).unwrap(), (0x1000, 64));
+        assert_eq!(bind_range(0x1000, 64, 64..64).unwrap(), (0x1040, 0));
+    }
+
+    #[test]
+    fn bind_range_outside_the_mr_is_rejected(){
+        assert!(matches!(bind_range(0x1000, 64, 0..65), Err(IbvError::InvalidArgument{ .. })));
+        assert!(bind_range(0x1000, 64, 32..16).is_err());
+        assert!(bind_range(0x1000, 0, 0..1).is_err());
+    }
+}
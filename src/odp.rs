@@ -0,0 +1,158 @@
+use std::ops::Range;
+use rdma_sys::*;
+
+use crate::{device_name, IbvAccessFlags, IbvDeviceAttr, IbvError, IbvMr, IbvPd, IbvQpType, Result};
+
+const ADVISE_MR_FLAG_FLUSH: u32 = 1;
+
+/// What `IbvMr::prefetch` asks the device to do with a range of an ODP MR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MrAdvice{
+    /// Fault the pages in for reading.
+    Prefetch,
+    /// Fault the pages in for writing.
+    PrefetchWrite,
+    /// Map only pages that are already present, without faulting.
+    PrefetchNoFault,
+}
+
+impl MrAdvice{
+    pub fn get(&self) -> ibv_advise_mr_advice{
+        match self{
+            MrAdvice::Prefetch => ib_uverbs_advise_mr_advice::IB_UVERBS_ADVISE_MR_ADVICE_PREFETCH,
+            MrAdvice::PrefetchWrite => ib_uverbs_advise_mr_advice::IB_UVERBS_ADVISE_MR_ADVICE_PREFETCH_WRITE,
+            MrAdvice::PrefetchNoFault => ib_uverbs_advise_mr_advice::IB_UVERBS_ADVISE_MR_ADVICE_PREFETCH_NO_FAULT,
+        }
+    }
+}
+
+// Checks the device's ODP caps for everything `access` allows on QPs of
+// `qp_type`. Every MR can be the source of a send.
+fn check_odp(pd: &IbvPd, access: IbvAccessFlags, qp_type: IbvQpType, implicit: bool) -> Result<()>{
+    let context = pd.context();
+    let caps = IbvDeviceAttr::query(context)?.odp_caps;
+    let unsupported = |operation| IbvError::OdpNotSupported{
+        device: device_name(context),
+        qp_type,
+        operation,
+    };
+    if !caps.is_supported() {
+        return Err(unsupported("any operation"));
+    }
+    if implicit && !caps.supports_implicit() {
+        return Err(unsupported("implicit MRs"));
+    }
+    let transport_caps = caps.transport_caps(qp_type);
+    let required = [
+        (true, ibv_odp_transport_cap_bits::IBV_ODP_SUPPORT_SEND, "send"),
+        (access.contains(IbvAccessFlags::LOCAL_WRITE), ibv_odp_transport_cap_bits::IBV_ODP_SUPPORT_RECV, "receive"),
+        (access.contains(IbvAccessFlags::REMOTE_WRITE), ibv_odp_transport_cap_bits::IBV_ODP_SUPPORT_WRITE, "RDMA write"),
+        (access.contains(IbvAccessFlags::REMOTE_READ), ibv_odp_transport_cap_bits::IBV_ODP_SUPPORT_READ, "RDMA read"),
+        (access.contains(IbvAccessFlags::REMOTE_ATOMIC), ibv_odp_transport_cap_bits::IBV_ODP_SUPPORT_ATOMIC, "atomics"),
+    ];
+    for (needed, bit, operation) in required{
+        if needed && transport_caps & bit.0 == 0 {
+            return Err(unsupported(operation));
+        }
+    }
+    Ok(())
+}
+
+impl IbvMr<'static>{
+    /// Registers `buf` with on-demand paging, so its pages are not pinned
+    /// and are faulted in by the device when accessed. Fails with
+    /// `OdpNotSupported` if the device cannot use ODP for what `access`
+    /// allows on QPs of `qp_type`.
+    pub fn from_vec_on_demand(pd: &IbvPd, buf: Vec<u8>, access: IbvAccessFlags, qp_type: IbvQpType) -> Result<Self>{
+        check_odp(pd, access, qp_type, false)?;
+        IbvMr::from_vec(pd, buf, access | IbvAccessFlags::ON_DEMAND)
+    }
+    /// Registers the whole address space of the process with on-demand
+    /// paging. Any memory can then be used with this MR's keys, using its
+    /// virtual address as the SGE address. The MR has no contents of its own.
+    pub fn implicit_on_demand(pd: &IbvPd, access: IbvAccessFlags, qp_type: IbvQpType) -> Result<Self>{
+        check_odp(pd, access, qp_type, true)?;
+        let mut mr = IbvMr::register(pd, std::ptr::null_mut(), usize::MAX, access | IbvAccessFlags::ON_DEMAND, None)?;
+        mr.implicit = true;
+        Ok(mr)
+    }
+}
+
+impl<'a> IbvMr<'a>{
+    /// Registers `buf` with on-demand paging, see `from_vec_on_demand`.
+    pub fn from_slice_on_demand(pd: &IbvPd, buf: &'a mut [u8], access: IbvAccessFlags, qp_type: IbvQpType) -> Result<Self>{
+        check_odp(pd, access, qp_type, false)?;
+        IbvMr::from_slice(pd, buf, access | IbvAccessFlags::ON_DEMAND)
+    }
+    /// Whether this is an implicit ODP MR covering the whole address space.
+    pub fn is_implicit(&self) -> bool{
+        self.implicit
+    }
+    /// Asks the device to fault in `range` of an ODP MR ahead of use, so the
+    /// first access does not stall on a page fault. The range is relative
+    /// to `addr`, which for an implicit MR is 0, so it holds virtual
+    /// addresses there. With `wait` the call returns once the pages are
+    /// mapped.
+    pub fn prefetch(&self, range: Range<u64>, advice: MrAdvice, wait: bool) -> Result<()>{
+        let mut sg_list = prefetch_sges(self.addr(), self.lkey(), self.length() as u64, self.implicit, range)?;
+        if sg_list.is_empty() {
+            return Ok(());
+        }
+        let flags = if wait { ADVISE_MR_FLAG_FLUSH } else { 0 };
+        let ret = unsafe{ ibv_advise_mr(self.pd.as_ptr(), advice.get(), flags, sg_list.as_mut_ptr(), sg_list.len() as u32) };
+        if ret != 0 {
+            return Err(IbvError::from_ret("ibv_advise_mr", ret).with_device(device_name(self.pd.context())));
+        }
+        Ok(())
+    }
+}
+
+// Splits `range` of an MR into SGEs for `ibv_advise_mr`, each covering at
+// most 4 GiB. An implicit MR has no length to check the range against.
+fn prefetch_sges(addr: u64, lkey: u32, length: u64, implicit: bool, range: Range<u64>) -> Result<Vec<ibv_sge>>{
+    if range.start > range.end || (!implicit && range.end > length) {
+        return Err(IbvError::invalid_argument("IbvMr::prefetch", format!("range {:?} is outside the MR of {} bytes", range, length)));
+    }
+    let mut sg_list = Vec::new();
+    let mut start = range.start;
+    while start < range.end {
+        let sge_length = (range.end - start).min(u32::MAX as u64);
+        sg_list.push(ibv_sge{
+            addr: addr + start,
+            length: sge_length as u32,
+            lkey,
+        });
+        start += sge_length;
+    }
+    Ok(sg_list)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn spans(sg_list: &[ibv_sge]) -> Vec<(u64, u32)>{
+        sg_list.iter().map(|sge| (sge.addr, sge.length)).collect()
+    }
+
+    #[test]
+    fn range_is_checked_against_the_mr(){
+        assert!(matches!(prefetch_sges(0x1000, 7, 64, false, 0..65), Err(IbvError::InvalidArgument{ .. })));
+        assert!(prefetch_sges(0x1000, 7, 64, false, 32..16).is_err());
+        assert!(prefetch_sges(0, 7, 0, true, 32..16).is_err());
+        assert_eq!(spans(&prefetch_sges(0, 7, 0, true, (1 << 40)..(1 << 40) + 8).unwrap()), [(1 << 40, 8)]);
+    }
+
+    #[test]
+    fn empty_range_needs_no_sge(){
+        assert!(prefetch_sges(0x1000, 7, 64, false, 8..8).unwrap().is_empty());
+    }
+
+    #[test]
+    fn large_ranges_are_split_at_4_gib(){
+        let max = u32::MAX as u64;
+        let sg_list = prefetch_sges(0x1000, 7, 3 * max, false, 16..2 * max + 32).unwrap();
+        assert_eq!(spans(&sg_list), [(0x1010, u32::MAX), (0x1010 + max, u32::MAX), (0x1010 + 2 * max, 16)]);
+        assert!(sg_list.iter().all(|sge| sge.lkey == 7));
+    }
+}
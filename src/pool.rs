@@ -0,0 +1,553 @@
+use std::{ops::{Deref, DerefMut}, sync::{Arc, Condvar, Mutex}};
+use log::info;
+
+use crate::{HugeBuffer, HugePageSize, IbvAccessFlags, IbvError, IbvMr, IbvPd, IbvSge, Result};
+
+/// What `MrPool::alloc` does when no buffer of the requested size is free.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Exhaustion{
+    /// Wait until another buffer of the size class is returned.
+    Block,
+    /// Register another chunk for the size class.
+    Grow,
+    /// Return `IbvError::PoolExhausted`.
+    #[default]
+    Fail,
+}
+
+/// Usage of one size class.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeClassStats{
+    pub size: usize,
+    pub chunks: usize,
+    pub buffers: usize,
+    pub in_use: usize,
+    pub peak_in_use: usize,
+    /// Allocations that found the class exhausted, whatever the policy did.
+    pub exhausted: u64,
+}
+
+/// A snapshot of the pool's usage.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MrPoolStats{
+    pub classes: Vec<SizeClassStats>,
+    pub allocations: u64,
+    /// Allocations that failed, either because the pool was exhausted with
+    /// the `Fail` policy or because registering a new chunk failed.
+    pub failures: u64,
+}
+
+impl MrPoolStats{
+    pub fn registered_bytes(&self) -> usize{
+        self.classes.iter().map(|class| class.buffers * class.size).sum()
+    }
+    pub fn in_use_bytes(&self) -> usize{
+        self.classes.iter().map(|class| class.in_use * class.size).sum()
+    }
+    /// Fraction of the registered memory handed out, between 0 and 1.
+    pub fn utilization(&self) -> f64{
+        match self.registered_bytes(){
+            0 => 0.0,
+            registered => self.in_use_bytes() as f64 / registered as f64,
+        }
+    }
+}
+
+struct SizeClass<C>{
+    size: usize,
+    chunks: Vec<C>,
+    // (chunk index, offset) of the free buffers.
+    free: Vec<(usize, usize)>,
+    stats: SizeClassStats,
+}
+
+// The outcome of `PoolState::try_alloc`.
+#[derive(Debug, PartialEq, Eq)]
+enum Alloc{
+    // The chunk index and offset of the taken buffer.
+    Taken(usize, usize),
+    // Wait for a buffer to be returned and try again.
+    Wait,
+    // Register a chunk of this many bytes, add it and try again.
+    Grow(usize),
+}
+
+// The free lists and statistics, generic over the registered chunk so
+// the accounting can be tested without a device.
+struct PoolState<C>{
+    classes: Vec<SizeClass<C>>,
+    buffers_per_chunk: usize,
+    allocations: u64,
+    failures: u64,
+}
+
+impl<C> PoolState<C>{
+    fn new(sizes: &[usize], buffers_per_chunk: usize) -> Self{
+        PoolState{
+            classes: sizes.iter().map(|&size| SizeClass{
+                size,
+                chunks: Vec::new(),
+                free: Vec::new(),
+                stats: SizeClassStats{
+                    size,
+                    ..Default::default()
+                },
+            }).collect(),
+            buffers_per_chunk,
+            allocations: 0,
+            failures: 0,
+        }
+    }
+    // The smallest class that fits `len`.
+    fn class_for(&self, len: usize) -> Option<usize>{
+        self.classes.iter().position(|class| class.size >= len)
+    }
+    // Adds the buffers of a newly registered chunk to the free list.
+    fn add_chunk(&mut self, class_idx: usize, chunk: C){
+        let buffers_per_chunk = self.buffers_per_chunk;
+        let class = &mut self.classes[class_idx];
+        let chunk_idx = class.chunks.len();
+        class.chunks.push(chunk);
+        class.free.extend((0..buffers_per_chunk).rev().map(|i| (chunk_idx, i * class.size)));
+        class.stats.chunks += 1;
+        class.stats.buffers += buffers_per_chunk;
+        info!("MrPool registered chunk {} for size class {}", chunk_idx, class.size);
+    }
+    // Takes a free buffer of the class for `len` bytes, applying `exhaustion`
+    // if there is none. `retry` is set when trying again after `Wait` or
+    // `Grow`, so the class is counted as exhausted only once per allocation.
+    fn try_alloc(&mut self, class_idx: usize, len: usize, exhaustion: Exhaustion, retry: bool) -> Result<Alloc>{
+        let buffers_per_chunk = self.buffers_per_chunk;
+        let class = &mut self.classes[class_idx];
+        let Some((chunk_idx, offset)) = class.free.pop() else {
+            if !retry {
+                class.stats.exhausted += 1;
+            }
+            return match exhaustion{
+                Exhaustion::Block => Ok(Alloc::Wait),
+                Exhaustion::Grow => Ok(Alloc::Grow(class.size * buffers_per_chunk)),
+                Exhaustion::Fail => {
+                    self.failures += 1;
+                    Err(IbvError::PoolExhausted{ size: len })
+                },
+            };
+        };
+        class.stats.in_use += 1;
+        class.stats.peak_in_use = class.stats.peak_in_use.max(class.stats.in_use);
+        self.allocations += 1;
+        Ok(Alloc::Taken(chunk_idx, offset))
+    }
+    // Counts an allocation whose chunk for `Alloc::Grow` failed to register.
+    fn grow_failed(&mut self){
+        self.failures += 1;
+    }
+    fn give_back(&mut self, class_idx: usize, chunk_idx: usize, offset: usize){
+        let class = &mut self.classes[class_idx];
+        class.free.push((chunk_idx, offset));
+        class.stats.in_use -= 1;
+    }
+    fn stats(&self) -> MrPoolStats{
+        MrPoolStats{
+            classes: self.classes.iter().map(|class| class.stats).collect(),
+            allocations: self.allocations,
+            failures: self.failures,
+        }
+    }
+}
+
+struct PoolInner{
+    pd: IbvPd,
+    access: IbvAccessFlags,
+    exhaustion: Exhaustion,
+    hugepages: Option<HugePageSize>,
+    numa_node: Option<u32>,
+    state: Mutex<PoolState<IbvMr<'static>>>,
+    returned: Condvar,
+}
+
+impl PoolInner{
+    // Registers a chunk of `len` bytes.
+    fn register(&self, len: usize) -> Result<IbvMr<'static>>{
+        match (self.hugepages, self.numa_node){
+            (Some(page_size), Some(node)) => IbvMr::from_huge_buffer(&self.pd, HugeBuffer::on_node(len, page_size, node)?, self.access),
+            (Some(page_size), None) => IbvMr::from_huge_buffer(&self.pd, HugeBuffer::new(len, page_size)?, self.access),
+            (None, _) => IbvMr::from_vec(&self.pd, vec![0; len], self.access),
+        }
+    }
+}
+
+/// Builds an `MrPool`.
+///
+/// By default the pool has one size class of 4096 bytes with 64 buffers per
+/// chunk, local write access and the `Fail` policy.
+pub struct MrPoolBuilder<'a>{
+    pd: &'a IbvPd,
+    sizes: Vec<usize>,
+    buffers_per_chunk: usize,
+    initial_chunks: usize,
+    access: IbvAccessFlags,
+    exhaustion: Exhaustion,
+    hugepages: Option<HugePageSize>,
+    numa_node: Option<u32>,
+}
+
+impl<'a> MrPoolBuilder<'a>{
+    pub fn new(pd: &'a IbvPd) -> Self{
+        MrPoolBuilder{
+            pd,
+            sizes: vec![4096],
+            buffers_per_chunk: 64,
+            initial_chunks: 1,
+            access: IbvAccessFlags::LOCAL_WRITE,
+            exhaustion: Exhaustion::default(),
+            hugepages: None,
+            numa_node: None,
+        }
+    }
+    /// Hands out buffers of a single size.
+    pub fn buffer_size(mut self, size: usize) -> Self{
+        self.sizes = vec![size];
+        self
+    }
+    /// Hands out the smallest of `sizes` that fits a request.
+    pub fn size_classes(mut self, sizes: &[usize]) -> Self{
+        self.sizes = sizes.to_vec();
+        self
+    }
+    /// Number of buffers registered together in one MR.
+    pub fn buffers_per_chunk(mut self, buffers_per_chunk: usize) -> Self{
+        self.buffers_per_chunk = buffers_per_chunk;
+        self
+    }
+    /// Chunks registered per size class when the pool is built. Has to be
+    /// at least 1 with `Exhaustion::Block`.
+    pub fn initial_chunks(mut self, initial_chunks: usize) -> Self{
+        self.initial_chunks = initial_chunks;
+        self
+    }
+    pub fn access(mut self, access: IbvAccessFlags) -> Self{
+        self.access = access;
+        self
+    }
+    pub fn exhaustion(mut self, exhaustion: Exhaustion) -> Self{
+        self.exhaustion = exhaustion;
+        self
+    }
+    /// Backs the chunks with hugepages, see `HugeBuffer`.
+    pub fn hugepages(mut self, page_size: HugePageSize) -> Self{
+        self.hugepages = Some(page_size);
+        self
+    }
+    /// Places hugepage backed chunks on NUMA node `node`, usually the one
+    /// from `IbvContext::numa_node`. Requires `hugepages`, `build` fails
+    /// with EINVAL otherwise.
+    pub fn numa_node(mut self, node: u32) -> Self{
+        self.numa_node = Some(node);
+        self
+    }
+    pub fn build(self) -> Result<MrPool>{
+        let mut sizes = self.sizes;
+        sizes.sort_unstable();
+        sizes.dedup();
+        validate(&sizes, self.buffers_per_chunk, self.initial_chunks, self.exhaustion, self.hugepages, self.numa_node)?;
+        let inner = PoolInner{
+            pd: self.pd.clone(),
+            access: self.access,
+            exhaustion: self.exhaustion,
+            hugepages: self.hugepages,
+            numa_node: self.numa_node,
+            state: Mutex::new(PoolState::new(&sizes, self.buffers_per_chunk)),
+            returned: Condvar::new(),
+        };
+        {
+            let mut state = inner.state.lock().unwrap();
+            for (class_idx, &size) in sizes.iter().enumerate(){
+                for _ in 0..self.initial_chunks{
+                    state.add_chunk(class_idx, inner.register(size * self.buffers_per_chunk)?);
+                }
+            }
+        }
+        Ok(MrPool{
+            inner: Arc::new(inner),
+        })
+    }
+}
+
+// Checks the builder's settings, with `sizes` sorted and deduplicated.
+fn validate(
+    sizes: &[usize],
+    buffers_per_chunk: usize,
+    initial_chunks: usize,
+    exhaustion: Exhaustion,
+    hugepages: Option<HugePageSize>,
+    numa_node: Option<u32>,
+) -> Result<()>{
+    let (Some(&smallest), Some(&largest)) = (sizes.first(), sizes.last()) else {
+        return Err(IbvError::invalid_argument("MrPoolBuilder::build", "no size classes"));
+    };
+    if smallest == 0 || buffers_per_chunk == 0 {
+        return Err(IbvError::invalid_argument("MrPoolBuilder::build", "a size of 0 or 0 buffers per chunk"));
+    }
+    if exhaustion == Exhaustion::Block && initial_chunks == 0 {
+        return Err(IbvError::invalid_argument("MrPoolBuilder::build", "the Block policy waits forever without initial chunks"));
+    }
+    if numa_node.is_some() && hugepages.is_none() {
+        return Err(IbvError::invalid_argument("MrPoolBuilder::build", "numa_node requires hugepages"));
+    }
+    // An SGE length is 32 bits wide.
+    if largest > u32::MAX as usize {
+        return Err(IbvError::invalid_argument("MrPoolBuilder::build", format!("buffer size {} exceeds u32::MAX", largest)));
+    }
+    if largest.checked_mul(buffers_per_chunk).is_none() {
+        return Err(IbvError::invalid_argument("MrPoolBuilder::build", format!("{} buffers of {} bytes overflow a chunk", buffers_per_chunk, largest)));
+    }
+    Ok(())
+}
+
+/// Buffers carved out of a few large registered chunks, so the data path
+/// does not have to register memory per message.
+///
+/// Buffers go back to the pool when they are dropped. The pool is a cheap
+/// handle, clones share the same buffers.
+#[derive(Clone)]
+pub struct MrPool{
+    inner: Arc<PoolInner>,
+}
+
+impl MrPool{
+    /// A pool of `count` buffers of `size` bytes in a single chunk.
+    pub fn new(pd: &IbvPd, size: usize, count: usize, access: IbvAccessFlags) -> Result<Self>{
+        MrPoolBuilder::new(pd)
+            .buffer_size(size)
+            .buffers_per_chunk(count)
+            .access(access)
+            .build()
+    }
+    pub fn builder(pd: &IbvPd) -> MrPoolBuilder<'_>{
+        MrPoolBuilder::new(pd)
+    }
+    /// Takes a buffer of at least `len` bytes from the smallest size class
+    /// that fits. What happens if that class has no free buffer depends on
+    /// the pool's `Exhaustion` policy.
+    pub fn alloc(&self, len: usize) -> Result<PoolBuf>{
+        let mut state = self.inner.state.lock().unwrap();
+        let class_idx = match state.class_for(len){
+            Some(class_idx) => class_idx,
+            None => return Err(IbvError::invalid_argument("MrPool::alloc", format!("{} bytes exceed the largest size class", len))),
+        };
+        let mut retry = false;
+        loop{
+            let (chunk_idx, offset) = match state.try_alloc(class_idx, len, self.inner.exhaustion, retry)?{
+                Alloc::Taken(chunk_idx, offset) => (chunk_idx, offset),
+                Alloc::Wait => {
+                    state = self.inner.returned.wait(state).unwrap();
+                    retry = true;
+                    continue;
+                },
+                Alloc::Grow(chunk_len) => {
+                    // Registering is slow, so other threads keep allocating
+                    // and returning buffers meanwhile. If several grow the
+                    // class at once, each adds its chunk.
+                    drop(state);
+                    let chunk = self.inner.register(chunk_len);
+                    state = self.inner.state.lock().unwrap();
+                    match chunk{
+                        Ok(chunk) => state.add_chunk(class_idx, chunk),
+                        Err(err) => {
+                            state.grow_failed();
+                            return Err(err);
+                        },
+                    }
+                    retry = true;
+                    continue;
+                },
+            };
+            let class = &state.classes[class_idx];
+            let chunk = &class.chunks[chunk_idx];
+            return Ok(PoolBuf{
+                pool: self.inner.clone(),
+                class: class_idx,
+                chunk: chunk_idx,
+                offset,
+                len,
+                capacity: class.size,
+                addr: chunk.addr() + offset as u64,
+                lkey: chunk.lkey(),
+                rkey: chunk.rkey(),
+            });
+        }
+    }
+    pub fn stats(&self) -> MrPoolStats{
+        self.inner.state.lock().unwrap().stats()
+    }
+}
+
+/// A buffer taken from an `MrPool`, returned to it on drop.
+pub struct PoolBuf{
+    pool: Arc<PoolInner>,
+    class: usize,
+    chunk: usize,
+    offset: usize,
+    len: usize,
+    capacity: usize,
+    addr: u64,
+    lkey: u32,
+    rkey: u32,
+}
+
+impl PoolBuf{
+    /// Address of the buffer, as used in SGEs and by remote peers.
+    pub fn addr(&self) -> u64{
+        self.addr
+    }
+    pub fn lkey(&self) -> u32{
+        self.lkey
+    }
+    pub fn rkey(&self) -> u32{
+        self.rkey
+    }
+    /// Offset of the buffer within the MR of its chunk.
+    pub fn offset(&self) -> usize{
+        self.offset
+    }
+    /// Size of the buffer's size class.
+    pub fn capacity(&self) -> usize{
+        self.capacity
+    }
+    /// Changes the length seen through `as_slice`. Fails with EINVAL if
+    /// `len` exceeds the capacity.
+    pub fn set_len(&mut self, len: usize) -> Result<()>{
+        if len > self.capacity {
+            return Err(IbvError::invalid_argument("PoolBuf::set_len", format!("length {} exceeds capacity {}", len, self.capacity)));
+        }
+        self.len = len;
+        Ok(())
+    }
+    /// An SGE covering the first `len` bytes of the buffer.
+    pub fn sge(&self) -> IbvSge{
+        // The builder caps the capacity at u32::MAX, so this does not truncate.
+        IbvSge::new(self.addr, self.len as u32, self.lkey)
+    }
+    pub fn as_slice(&self) -> &[u8]{
+        unsafe{ std::slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+    pub fn as_mut_slice(&mut self) -> &mut [u8]{
+        unsafe{ std::slice::from_raw_parts_mut(self.addr as *mut u8, self.len) }
+    }
+}
+
+impl Deref for PoolBuf{
+    type Target = [u8];
+    fn deref(&self) -> &[u8]{
+        self.as_slice()
+    }
+}
+
+impl DerefMut for PoolBuf{
+    fn deref_mut(&mut self) -> &mut [u8]{
+        self.as_mut_slice()
+    }
+}
+
+impl Drop for PoolBuf{
+    fn drop(&mut self){
+        self.pool.state.lock().unwrap().give_back(self.class, self.chunk, self.offset);
+        self.pool.returned.notify_all();
+    }
+}
+
+unsafe impl Send for PoolBuf{}
+unsafe impl Sync for PoolBuf{}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn smallest_fitting_class(){
+        let state = PoolState::<()>::new(&[64, 256, 4096], 4);
+        assert_eq!(state.class_for(1), Some(0));
+        assert_eq!(state.class_for(64), Some(0));
+        assert_eq!(state.class_for(65), Some(1));
+        assert_eq!(state.class_for(4096), Some(2));
+        assert_eq!(state.class_for(4097), None);
+    }
+
+    #[test]
+    fn alloc_and_give_back_are_counted(){
+        let mut state = PoolState::new(&[64], 2);
+        state.add_chunk(0, ());
+        assert_eq!(state.try_alloc(0, 10, Exhaustion::Fail, false).unwrap(), Alloc::Taken(0, 0));
+        assert_eq!(state.try_alloc(0, 64, Exhaustion::Fail, false).unwrap(), Alloc::Taken(0, 64));
+        state.give_back(0, 0, 0);
+        let stats = state.stats();
+        assert_eq!(stats.allocations, 2);
+        assert_eq!(stats.failures, 0);
+        assert_eq!(stats.classes[0], SizeClassStats{ size: 64, chunks: 1, buffers: 2, in_use: 1, peak_in_use: 2, exhausted: 0 });
+        assert_eq!(state.try_alloc(0, 1, Exhaustion::Fail, false).unwrap(), Alloc::Taken(0, 0));
+    }
+
+    #[test]
+    fn exhaustion_fails(){
+        let mut state = PoolState::new(&[64], 1);
+        state.add_chunk(0, ());
+        state.try_alloc(0, 64, Exhaustion::Fail, false).unwrap();
+        let err = state.try_alloc(0, 32, Exhaustion::Fail, false).unwrap_err();
+        assert!(matches!(err, IbvError::PoolExhausted{ size: 32 }));
+        let stats = state.stats();
+        assert_eq!((stats.allocations, stats.failures, stats.classes[0].exhausted), (1, 1, 1));
+    }
+
+    #[test]
+    fn exhaustion_blocks_and_counts_once(){
+        let mut state = PoolState::new(&[64], 1);
+        state.add_chunk(0, ());
+        assert_eq!(state.try_alloc(0, 64, Exhaustion::Block, false).unwrap(), Alloc::Taken(0, 0));
+        assert_eq!(state.try_alloc(0, 64, Exhaustion::Block, false).unwrap(), Alloc::Wait);
+        assert_eq!(state.try_alloc(0, 64, Exhaustion::Block, true).unwrap(), Alloc::Wait);
+        state.give_back(0, 0, 0);
+        assert_eq!(state.try_alloc(0, 64, Exhaustion::Block, true).unwrap(), Alloc::Taken(0, 0));
+        let stats = state.stats();
+        assert_eq!((stats.allocations, stats.failures, stats.classes[0].exhausted), (2, 0, 1));
+    }
+
+    #[test]
+    fn exhaustion_grows(){
+        let mut state = PoolState::new(&[64], 2);
+        assert_eq!(state.try_alloc(0, 64, Exhaustion::Grow, false).unwrap(), Alloc::Grow(128));
+        state.add_chunk(0, ());
+        assert_eq!(state.try_alloc(0, 64, Exhaustion::Grow, true).unwrap(), Alloc::Taken(0, 0));
+        assert_eq!(state.try_alloc(0, 64, Exhaustion::Grow, false).unwrap(), Alloc::Taken(0, 64));
+        assert_eq!(state.try_alloc(0, 64, Exhaustion::Grow, false).unwrap(), Alloc::Grow(128));
+        state.add_chunk(0, ());
+        assert_eq!(state.try_alloc(0, 64, Exhaustion::Grow, true).unwrap(), Alloc::Taken(1, 0));
+        assert_eq!(state.try_alloc(0, 64, Exhaustion::Grow, false).unwrap(), Alloc::Taken(1, 64));
+        assert_eq!(state.try_alloc(0, 64, Exhaustion::Grow, false).unwrap(), Alloc::Grow(128));
+        state.grow_failed();
+        let stats = state.stats();
+        assert_eq!((stats.allocations, stats.failures), (4, 1));
+        assert_eq!((stats.classes[0].chunks, stats.classes[0].buffers, stats.classes[0].exhausted), (2, 4, 3));
+    }
+
+    #[test]
+    fn block_needs_initial_chunks(){
+        assert!(validate(&[64], 4, 1, Exhaustion::Block, None, None).is_ok());
+        assert!(matches!(validate(&[64], 4, 0, Exhaustion::Block, None, None), Err(IbvError::InvalidArgument{ .. })));
+        assert!(validate(&[64], 4, 0, Exhaustion::Grow, None, None).is_ok());
+    }
+
+    #[test]
+    fn numa_node_needs_hugepages(){
+        assert!(matches!(validate(&[64], 4, 1, Exhaustion::Fail, None, Some(0)), Err(IbvError::InvalidArgument{ .. })));
+        assert!(validate(&[64], 4, 1, Exhaustion::Fail, Some(HugePageSize::Size2M), Some(0)).is_ok());
+    }
+
+    #[test]
+    fn sizes_are_validated(){
+        assert!(validate(&[], 4, 1, Exhaustion::Fail, None, None).is_err());
+        assert!(validate(&[0, 64], 4, 1, Exhaustion::Fail, None, None).is_err());
+        assert!(validate(&[64], 0, 1, Exhaustion::Fail, None, None).is_err());
+        assert!(validate(&[u32::MAX as usize + 1], 1, 1, Exhaustion::Fail, None, None).is_err());
+        assert!(validate(&[1 << 31], usize::MAX, 1, Exhaustion::Fail, None, None).is_err());
+    }
+}
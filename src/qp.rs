@@ -0,0 +1,317 @@
+use std::{fmt, marker::PhantomData, ops::Range};
+use rdma_sys::*;
+
+use crate::{AsyncEvent, ConnectParams, IbvAccessFlags, IbvCq, IbvError, IbvMr, IbvPd, IbvQp, IbvQpCap, IbvRecvWr, IbvSendFlags, IbvSendWr, MrPool, Mtu, PendingSend, PendingWr, PostResult, QpBuilder, QpMetadata, RecvBatch, RemoteSlice, Result, SendBatch, WorkCompletion};
+
+/// The state of a QP as reported by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IbvQpState{
+    Reset,
+    Init,
+    Rtr,
+    Rts,
+    Sqd,
+    Sqe,
+    Error,
+    Unknown,
+}
+
+impl From<ibv_qp_state::Type> for IbvQpState{
+    fn from(state: ibv_qp_state::Type) -> Self{
+        match state{
+            ibv_qp_state::IBV_QPS_RESET => IbvQpState::Reset,
+            ibv_qp_state::IBV_QPS_INIT => IbvQpState::Init,
+            ibv_qp_state::IBV_QPS_RTR => IbvQpState::Rtr,
+            ibv_qp_state::IBV_QPS_RTS => IbvQpState::Rts,
+            ibv_qp_state::IBV_QPS_SQD => IbvQpState::Sqd,
+            ibv_qp_state::IBV_QPS_SQE => IbvQpState::Sqe,
+            ibv_qp_state::IBV_QPS_ERR => IbvQpState::Error,
+            _ => IbvQpState::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for IbvQpState{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        let name = match self{
+            IbvQpState::Reset => "RESET",
+            IbvQpState::Init => "INIT",
+            IbvQpState::Rtr => "RTR",
+            IbvQpState::Rts => "RTS",
+            IbvQpState::Sqd => "SQD",
+            IbvQpState::Sqe => "SQE",
+            IbvQpState::Error => "ERROR",
+            IbvQpState::Unknown => "UNKNOWN",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// QP attributes as returned by `ibv_query_qp`.
+#[derive(Debug, Clone, Copy)]
+pub struct IbvQpAttr{
+    pub state: IbvQpState,
+    pub path_mtu: Option<Mtu>,
+    pub sq_psn: u32,
+    pub rq_psn: u32,
+    pub dest_qp_num: u32,
+    pub qp_access_flags: IbvAccessFlags,
+    pub cap: IbvQpCap,
+    pub port_num: u8,
+    pub sl: u8,
+    pub traffic_class: u8,
+    pub hop_limit: u8,
+    pub sgid_index: u8,
+    pub max_rd_atomic: u8,
+    pub max_dest_rd_atomic: u8,
+    pub min_rnr_timer: u8,
+    pub timeout: u8,
+    pub retry_cnt: u8,
+    pub rnr_retry: u8,
+    pub sq_draining: bool,
+}
+
+impl IbvQpAttr{
+    pub(crate) fn query(qp: *mut ibv_qp) -> std::result::Result<Self, i32>{
+        let mut attr = unsafe{ std::mem::zeroed::<ibv_qp_attr>() };
+        let mut init_attr = unsafe{ std::mem::zeroed::<ibv_qp_init_attr>() };
+        let attr_mask = ibv_qp_attr_mask::IBV_QP_STATE |
+            ibv_qp_attr_mask::IBV_QP_CUR_STATE |
+            ibv_qp_attr_mask::IBV_QP_PATH_MTU |
+            ibv_qp_attr_mask::IBV_QP_SQ_PSN |
+            ibv_qp_attr_mask::IBV_QP_RQ_PSN |
+            ibv_qp_attr_mask::IBV_QP_DEST_QPN |
+            ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS |
+            ibv_qp_attr_mask::IBV_QP_CAP |
+            ibv_qp_attr_mask::IBV_QP_AV |
+            ibv_qp_attr_mask::IBV_QP_PORT |
+            ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC |
+            ibv_qp_attr_mask::IBV_QP_MAX_DEST_RD_ATOMIC |
+            ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER |
+            ibv_qp_attr_mask::IBV_QP_TIMEOUT |
+            ibv_qp_attr_mask::IBV_QP_RETRY_CNT |
+            ibv_qp_attr_mask::IBV_QP_RNR_RETRY;
+        let ret = unsafe{ ibv_query_qp(qp, &mut attr, attr_mask.0 as i32, &mut init_attr) };
+        if ret != 0 {
+            return Err(ret);
+        }
+        Ok(IbvQpAttr{
+            state: attr.qp_state.into(),
+            path_mtu: Mtu::from_ibv(attr.path_mtu),
+            sq_psn: attr.sq_psn,
+            rq_psn: attr.rq_psn,
+            dest_qp_num: attr.dest_qp_num,
+            qp_access_flags: IbvAccessFlags::from_bits_retain(attr.qp_access_flags),
+            cap: attr.cap.into(),
+            port_num: attr.port_num,
+            sl: attr.ah_attr.sl,
+            traffic_class: attr.ah_attr.grh.traffic_class,
+            hop_limit: attr.ah_attr.grh.hop_limit,
+            sgid_index: attr.ah_attr.grh.sgid_index,
+            max_rd_atomic: attr.max_rd_atomic,
+            max_dest_rd_atomic: attr.max_dest_rd_atomic,
+            min_rnr_timer: attr.min_rnr_timer,
+            timeout: attr.timeout,
+            retry_cnt: attr.retry_cnt,
+            rnr_retry: attr.rnr_retry,
+            sq_draining: attr.sq_draining != 0,
+        })
+    }
+}
+
+mod sealed{
+    pub trait Sealed{}
+}
+
+/// A QP state that is tracked in the type of `Qp`.
+pub trait QpState: sealed::Sealed{
+    const STATE: ibv_qp_state::Type;
+    const NAME: &'static str;
+}
+
+/// Freshly created, nothing can be posted yet.
+pub struct Reset;
+/// Receives can be posted, but nothing is received until RTR.
+pub struct Init;
+/// Ready to receive, receives can be posted.
+pub struct Rtr;
+/// Ready to send, sends and receives can be posted.
+pub struct Rts;
+
+macro_rules! qp_state{
+    ($ty:ident, $state:ident, $name:literal) => {
+        impl sealed::Sealed for $ty{}
+        impl QpState for $ty{
+            const STATE: ibv_qp_state::Type = ibv_qp_state::$state;
+            const NAME: &'static str = $name;
+        }
+    };
+}
+
+qp_state!(Reset, IBV_QPS_RESET, "RESET");
+qp_state!(Init, IBV_QPS_INIT, "INIT");
+qp_state!(Rtr, IBV_QPS_RTR, "RTR");
+qp_state!(Rts, IBV_QPS_RTS, "RTS");
+
+/// A QP whose state is part of its type.
+///
+/// Transitions consume the QP and return it in the next state, so posting
+/// a send before RTS or a receive before INIT does not compile. A failed
+/// transition drops the QP. A QP in the ERROR state is brought back to INIT
+/// with `recover` and connected again once the peer sent its new PSN.
+pub struct Qp<S: QpState>{
+    inner: IbvQp,
+    _state: PhantomData<S>,
+}
+
+impl<S: QpState> Qp<S>{
+    fn transition<T: QpState>(self) -> Qp<T>{
+        Qp{
+            inner: self.inner,
+            _state: PhantomData,
+        }
+    }
+    /// Wraps an untyped QP, failing if it is not actually in state `S`.
+    pub fn from_dynamic(qp: IbvQp) -> Result<Self>{
+        let state = qp.state()?;
+        if state != S::STATE.into() {
+            return Err(IbvError::QpState{
+                qp_num: qp.qp_num(),
+                expected: S::NAME,
+                actual: state,
+            });
+        }
+        Ok(Qp{
+            inner: qp,
+            _state: PhantomData,
+        })
+    }
+    /// Gives up the compile time state tracking.
+    pub fn into_dynamic(self) -> IbvQp{
+        self.inner
+    }
+    pub fn as_ptr(&self) -> *mut ibv_qp{
+        self.inner.as_ptr()
+    }
+    pub fn qp_num(&self) -> u32{
+        self.inner.qp_num()
+    }
+    pub fn psn(&self) -> u32{
+        self.inner.psn()
+    }
+    pub fn port(&self) -> u8{
+        self.inner.port()
+    }
+    pub fn cap(&self) -> IbvQpCap{
+        self.inner.cap()
+    }
+    pub fn access(&self) -> IbvAccessFlags{
+        self.inner.access()
+    }
+    pub fn pd(&self) -> &IbvPd{
+        self.inner.pd()
+    }
+    pub fn send_cq(&self) -> &IbvCq{
+        self.inner.send_cq()
+    }
+    pub fn recv_cq(&self) -> &IbvCq{
+        self.inner.recv_cq()
+    }
+    pub fn query(&self) -> Result<IbvQpAttr>{
+        self.inner.query()
+    }
+    pub fn state(&self) -> Result<IbvQpState>{
+        self.inner.state()
+    }
+    pub fn wait_for_event(&self) -> Result<WorkCompletion>{
+        self.inner.wait_for_event()
+    }
+    pub fn on_async_event<F: Fn(&AsyncEvent) + Send + Sync + 'static>(&self, callback: F){
+        self.inner.on_async_event(callback)
+    }
+    /// Moves the QP, typically in ERROR, back to INIT with a fresh PSN.
+    /// Exchange the new PSN with the peer and `connect` again.
+    pub fn recover(mut self) -> Result<Qp<Init>>{
+        self.inner.recover()?;
+        Ok(self.transition())
+    }
+}
+
+impl Qp<Reset>{
+    pub fn new(builder: QpBuilder) -> Result<Self>{
+        Ok(Qp{
+            inner: builder.build()?,
+            _state: PhantomData,
+        })
+    }
+    pub fn init(self, port: u8) -> Result<Qp<Init>>{
+        self.inner.init(port)?;
+        Ok(self.transition())
+    }
+}
+
+impl Qp<Init>{
+    pub fn post_recv(&self, recv_wr: IbvRecvWr) -> Result<()>{
+        self.inner.ibv_post_recv(recv_wr)
+    }
+    pub fn post_recv_batch(&self, batch: RecvBatch) -> PostResult<RecvBatch>{
+        self.inner.post_recv_batch(batch)
+    }
+    pub fn ready_to_receive(self, remote_qp_metadata: &QpMetadata, params: &ConnectParams) -> Result<Qp<Rtr>>{
+        self.inner.ready_to_receive(remote_qp_metadata, params)?;
+        Ok(self.transition())
+    }
+    /// Moves the QP through RTR to RTS.
+    pub fn connect(self, remote_qp_metadata: &QpMetadata, params: &ConnectParams) -> Result<Qp<Rts>>{
+        self.ready_to_receive(remote_qp_metadata, params)?
+            .ready_to_send(params)
+    }
+}
+
+impl Qp<Rtr>{
+    pub fn post_recv(&self, recv_wr: IbvRecvWr) -> Result<()>{
+        self.inner.ibv_post_recv(recv_wr)
+    }
+    pub fn post_recv_batch(&self, batch: RecvBatch) -> PostResult<RecvBatch>{
+        self.inner.post_recv_batch(batch)
+    }
+    pub fn ready_to_send(self, params: &ConnectParams) -> Result<Qp<Rts>>{
+        self.inner.ready_to_send(params)?;
+        Ok(self.transition())
+    }
+}
+
+impl Qp<Rts>{
+    pub fn post_send(&self, send_wr: IbvSendWr) -> Result<()>{
+        self.inner.ibv_post_send(send_wr)
+    }
+    pub fn post_send_batch(&self, batch: SendBatch) -> PostResult<SendBatch>{
+        self.inner.post_send_batch(batch)
+    }
+    /// See `IbvQp::send`.
+    pub fn send(&self, wr_id: u64, data: &[u8], pool: &MrPool) -> Result<PendingSend>{
+        self.inner.send(wr_id, data, pool)
+    }
+    /// See `IbvQp::post_send_inline`.
+    pub fn post_send_inline(&self, wr_id: u64, data: &[u8], send_flags: IbvSendFlags) -> Result<()>{
+        self.inner.post_send_inline(wr_id, data, send_flags)
+    }
+    /// See `IbvQp::write`.
+    pub fn write<'a>(&'a self, wr_id: u64, local: &'a IbvMr<'_>, range: Range<usize>, remote: RemoteSlice) -> Result<PendingWr<'a>>{
+        self.inner.write(wr_id, local, range, remote)
+    }
+    /// See `IbvQp::write_with_imm`.
+    pub fn write_with_imm<'a>(&'a self, wr_id: u64, local: &'a IbvMr<'_>, range: Range<usize>, remote: RemoteSlice, imm_data: u32) -> Result<PendingWr<'a>>{
+        self.inner.write_with_imm(wr_id, local, range, remote, imm_data)
+    }
+    /// See `IbvQp::read`.
+    pub fn read<'a>(&'a self, wr_id: u64, local: &'a mut IbvMr<'_>, range: Range<usize>, remote: RemoteSlice) -> Result<PendingWr<'a>>{
+        self.inner.read(wr_id, local, range, remote)
+    }
+    pub fn post_recv(&self, recv_wr: IbvRecvWr) -> Result<()>{
+        self.inner.ibv_post_recv(recv_wr)
+    }
+    pub fn post_recv_batch(&self, batch: RecvBatch) -> PostResult<RecvBatch>{
+        self.inner.post_recv_batch(batch)
+    }
+}
@@ -1,18 +1,27 @@
-use std::{io::{Read, Write}, net::{IpAddr, TcpListener}, thread};
+use std::{collections::HashMap, io::{Read, Write}, net::{IpAddr, SocketAddr, TcpListener}, thread};
 use log::info;
 
-use crate::{Hints, IbvAccessFlags, IbvDevice, IbvMr, IbvPd, IbvQp, IbvSendWr, IbvSge, IbvWrOpcode, LookUpBy, MrMetadata, QpMetadata, SocketComm, SocketCommCommand};
+use crate::{ConnectParams, Hints, IbvAccessFlags, IbvDevice, IbvMr, IbvPd, IbvSendFlags, IbvSendWr, IbvWrOpcode, Init, LookUpBy, MrMetadata, Qp, QpBuilder, QpMetadata, Rts, SocketComm, SocketCommCommand};
 
 pub struct Receiver{
     device: IbvDevice,
     listen_socket_port: u16,
+    // Set by `listen`, where `accept_recovery` listens again.
+    listen_address: Option<IpAddr>,
     receiver_metadata: MrMetadata,
-    receiver_metadata_mr: IbvMr,
+    receiver_metadata_mr: IbvMr<'static>,
     sender_metadata_address: u64,
     sender_metadata_rkey: u32,
     pub pd: IbvPd,
-    pub qp_list: Vec<IbvQp>,
+    /// QPs set up by `listen`, waiting for `connect`.
+    pub init_qp_list: Vec<Qp<Init>>,
+    // None while the QP is recovered, or if recovering it failed.
+    qp_list: Vec<Option<Qp<Rts>>>,
+    // QPs between `recover_qp` and `reconnect_qp`, by index.
+    recovering_qps: HashMap<usize, Qp<Init>>,
     qp_metadata_list: Vec<QpMetadata>,
+    qp_params_list: Vec<ConnectParams>,
+    connect_params: ConnectParams,
 }
 
 impl Receiver {
@@ -23,25 +32,34 @@ impl Receiver {
         if device.context.as_ptr().is_null() {
             return Err(anyhow::anyhow!("Device context is null"));
         }
-        let pd = IbvPd::new(&device.context);
+        let pd = IbvPd::new(&device.context)?;
         info!("Receiver created pd");
         let receiver_metadata = MrMetadata::default();
         info!("Receiver created metadata");
-        let access_flags = IbvAccessFlags::LocalWrite.as_i32() | IbvAccessFlags::RemoteWrite.as_i32() | IbvAccessFlags::RemoteRead.as_i32();
-        let receiver_metadata_mr = IbvMr::new(&pd, receiver_metadata.addr(), MrMetadata::SIZE, access_flags);
+        let access_flags = IbvAccessFlags::local_and_remote();
+        let receiver_metadata_mr = IbvMr::from_vec(&pd, vec![0; MrMetadata::SIZE], access_flags)?;
         info!("Receiver created metadata memory region with addr: {}, rkey: {}", receiver_metadata_mr.addr(), receiver_metadata_mr.rkey());
         Ok(Receiver{
             device,
             listen_socket_port,
+            listen_address: None,
             receiver_metadata,
             sender_metadata_address: 0,
             sender_metadata_rkey: 0,
             receiver_metadata_mr,
             pd,
+            init_qp_list: Vec::new(),
             qp_list: Vec::new(),
+            recovering_qps: HashMap::new(),
             qp_metadata_list: Vec::new(),
+            qp_params_list: Vec::new(),
+            connect_params: ConnectParams::default(),
         })
     }
+    /// Parameters proposed to the sender for every QP; applied on `connect`.
+    pub fn set_connect_params(&mut self, connect_params: ConnectParams) {
+        self.connect_params = connect_params;
+    }
     pub fn listen(&mut self, hints: Hints) -> anyhow::Result<()> {
         info!("Receiver starts to listen on port {}", self.listen_socket_port);
         let address = match hints{
@@ -60,6 +78,7 @@ impl Receiver {
                 }
             },
         };
+        self.listen_address = Some(address);
 
         let (out_tx, out_rx) = std::sync::mpsc::channel();
         let (in_tx, in_rx) = std::sync::mpsc::channel();
@@ -82,18 +101,24 @@ impl Receiver {
                     info!("Receiver received qp init command");
                     let gid_entry = self.device.gid_table.get_entry_by_index(idx as usize, family.clone());
                     if let Some((_ip_addr, gid_entry)) = gid_entry{
-                        let qp = IbvQp::new(&self.pd, &self.device.context, gid_entry.gidx(), gid_entry.port());
-                        qp.init(gid_entry.port)?;
+                        let qp = QpBuilder::new(&self.pd)
+                            .gid_index(gid_entry.gidx())
+                            .port(gid_entry.port())
+                            .build_typed()?
+                            .init(gid_entry.port)?;
                         let qpn = qp.qp_num();
                         let psn = qp.psn();
-                        self.qp_list.push(qp);
+                        let params = self.connect_params.clamp_to(&self.device.attr()?, &self.device.port_attr(gid_entry.port())?);
+                        self.init_qp_list.push(qp);
+                        self.qp_params_list.push(params);
                         let subnet_id = gid_entry.subnet_id();
                         let interface_id = gid_entry.interface_id();
                         let qp_metadata = QpMetadata{
                             subnet_id,
                             interface_id,
                             qpn,
-                            psn
+                            psn,
+                            params,
                         };
                         in_tx.send(SocketCommCommand::ConnectQp(qp_metadata)).unwrap();
                     } else {
@@ -105,6 +130,9 @@ impl Receiver {
                     self.qp_metadata_list.push(qp_metadata);
                     in_tx.send(SocketCommCommand::ConnectQp(QpMetadata::default())).unwrap();
                 },
+                SocketCommCommand::RecoverQp(idx, _psn) => {
+                    return Err(anyhow::anyhow!("Received a recovery request for QP {} while connecting", idx));
+                },
                 SocketCommCommand::Stop => { 
                     in_tx.send(SocketCommCommand::Stop).unwrap();
                     info!("Receiver received stop command");
@@ -114,24 +142,76 @@ impl Receiver {
         }
         Ok(())
     }
+    /// The connected QP at `qp_idx`, or `None` while it is recovered or if
+    /// recovering it failed.
+    pub fn qp(&self, qp_idx: usize) -> Option<&Qp<Rts>> {
+        self.qp_list.get(qp_idx)?.as_ref()
+    }
+    /// Moves a connected QP that went into the ERROR state back to INIT and
+    /// returns its new PSN. Once the sender did the same and both sides
+    /// have the other's new PSN, `reconnect_qp` connects the QP again. If
+    /// recovery fails the QP is dropped.
+    pub fn recover_qp(&mut self, qp_idx: usize) -> anyhow::Result<u32> {
+        let qp = self.qp_list.get_mut(qp_idx).and_then(Option::take).ok_or_else(|| anyhow::anyhow!("No connected QP with index {}", qp_idx))?;
+        let qp = qp.recover()?;
+        let psn = qp.psn();
+        info!("Receiver recovered QP {} with psn {}", qp.qp_num(), psn);
+        self.recovering_qps.insert(qp_idx, qp);
+        Ok(psn)
+    }
+    /// Connects a QP taken to INIT by `recover_qp` to the remote QP, which
+    /// was recovered with `remote_psn`.
+    pub fn reconnect_qp(&mut self, qp_idx: usize, remote_psn: u32) -> anyhow::Result<()> {
+        let qp = self.recovering_qps.remove(&qp_idx).ok_or_else(|| anyhow::anyhow!("QP {} is not being recovered", qp_idx))?;
+        let remote_qp_metadata = &mut self.qp_metadata_list[qp_idx];
+        remote_qp_metadata.psn = remote_psn;
+        let qp = qp.connect(remote_qp_metadata, &self.qp_params_list[qp_idx])?;
+        info!("Receiver reconnected QP {}", qp.qp_num());
+        self.qp_list[qp_idx] = Some(qp);
+        Ok(())
+    }
+    /// Waits on the listen address for a `Sender::recover_qp_with_receiver`
+    /// and recovers the QP it names, replying with the new PSN in a
+    /// `RecoverQp` message before connecting again. Returns the index of
+    /// the recovered QP.
+    pub fn accept_recovery(&mut self) -> anyhow::Result<usize> {
+        let address = self.listen_address.ok_or_else(|| anyhow::anyhow!("Receiver has not listened yet"))?;
+        let listener = TcpListener::bind(SocketAddr::new(address, self.listen_socket_port))?;
+        let (mut stream, _) = listener.accept()?;
+        let mut buffer = vec![0; 1024];
+        let len = stream.read(&mut buffer)?;
+        let socket_comm: SocketComm = bincode::deserialize(&buffer[..len])?;
+        let SocketCommCommand::RecoverQp(qp_idx, remote_psn) = socket_comm.command else {
+            return Err(anyhow::anyhow!("Expected a recovery request from the sender"));
+        };
+        let qp_idx = qp_idx as usize;
+        let psn = self.recover_qp(qp_idx)?;
+        let socket_comm = SocketComm{
+            command: SocketCommCommand::RecoverQp(qp_idx as u32, psn),
+        };
+        stream.write_all(&bincode::serialize(&socket_comm)?)?;
+        self.reconnect_qp(qp_idx, remote_psn)?;
+        Ok(qp_idx)
+    }
     pub fn connect(&mut self) -> anyhow::Result<()> {
-        for (qp_idx, qp) in self.qp_list.iter().enumerate() {
+        let init_qp_list = std::mem::take(&mut self.init_qp_list);
+        for (qp_idx, qp) in init_qp_list.into_iter().enumerate() {
             let remote_qp_metadata = self.qp_metadata_list.get(qp_idx).unwrap();
-            qp.connect(remote_qp_metadata)?;
-            let sge = IbvSge::new(self.metadata_addr(), MrMetadata::SIZE as u32, self.metadata_lkey());
-            let flags = IbvAccessFlags::LocalWrite.as_i32() | IbvAccessFlags::RemoteWrite.as_i32() | IbvAccessFlags::RemoteRead.as_i32();
-            let send_wr = IbvSendWr::new(
+            let params = self.qp_params_list[qp_idx].negotiate(&remote_qp_metadata.params);
+            self.qp_params_list[qp_idx] = params;
+            let qp = qp.connect(remote_qp_metadata, &params)?;
+            let send_wr = IbvSendWr::from_mrs(
                 0,
-                sge,
-                1,
+                &[(&self.receiver_metadata_mr, 0..MrMetadata::SIZE)],
                 IbvWrOpcode::Send,
-                flags,
+                IbvSendFlags::SIGNALED,
                 self.sender_metadata_address,
                 self.sender_metadata_rkey,
-            );
+            )?;
             info!("Receiver posting send");
-            qp.ibv_post_send(send_wr)?;
+            qp.post_send(send_wr)?;
             info!("Receiver send posted");
+            self.qp_list.push(Some(qp));
         }
         Ok(())
     }
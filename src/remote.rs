@@ -0,0 +1,554 @@
+use std::{collections::{HashMap, VecDeque}, marker::PhantomData, ops::Range, sync::{Condvar, Mutex}, time::{Duration, Instant}};
+use log::warn;
+
+use crate::{device_name, IbvAccessFlags, IbvCq, IbvDeviceAttr, IbvError, IbvMr, IbvQp, IbvSendFlags, IbvSendWr, IbvSge, IbvWrOpcode, MrMetadata, Qp, Result, Rts, WorkCompletion};
+
+/// A peer's MR, as advertised in the `MrMetadata` it sent, which can be
+/// the target of RDMA writes and the source of RDMA reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteBuffer{
+    addr: u64,
+    rkey: u32,
+    length: u64,
+}
+
+impl RemoteBuffer{
+    pub fn new(addr: u64, rkey: u32, length: u64) -> Self{
+        RemoteBuffer{
+            addr,
+            rkey,
+            length,
+        }
+    }
+    pub fn addr(&self) -> u64{
+        self.addr
+    }
+    pub fn rkey(&self) -> u32{
+        self.rkey
+    }
+    pub fn len(&self) -> u64{
+        self.length
+    }
+    pub fn is_empty(&self) -> bool{
+        self.length == 0
+    }
+    /// The part of the buffer at `range`, relative to its start. Fails if
+    /// the range is not within the advertised length or the peer sent an
+    /// address that would wrap around.
+    pub fn slice(&self, range: Range<u64>) -> Result<RemoteSlice>{
+        let invalid = || IbvError::invalid_argument("RemoteBuffer::slice", format!("range {:?} is outside the remote buffer of {} bytes at {:#x}", range, self.length, self.addr));
+        if range.start > range.end || range.end > self.length {
+            return Err(invalid());
+        }
+        let addr = self.addr.checked_add(range.start).ok_or_else(invalid)?;
+        addr.checked_add(range.end - range.start).ok_or_else(invalid)?;
+        Ok(RemoteSlice{
+            addr,
+            rkey: self.rkey,
+            length: range.end - range.start,
+        })
+    }
+    /// The 8 bytes at `offset` as the target of remote atomics, see
+    /// `RemoteAtomicU64::new`.
+    pub fn atomic_u64(&self, qp: &Qp<Rts>, offset: u64) -> Result<RemoteAtomicU64>{
+        RemoteAtomicU64::new(qp, self.slice(offset..offset.saturating_add(8))?)
+    }
+    /// The whole buffer.
+    pub fn as_slice(&self) -> RemoteSlice{
+        RemoteSlice{
+            addr: self.addr,
+            rkey: self.rkey,
+            length: self.length,
+        }
+    }
+}
+
+impl From<MrMetadata> for RemoteBuffer{
+    fn from(metadata: MrMetadata) -> Self{
+        RemoteBuffer::new(metadata.address, metadata.rkey, metadata.length)
+    }
+}
+
+impl From<&MrMetadata> for RemoteBuffer{
+    fn from(metadata: &MrMetadata) -> Self{
+        RemoteBuffer::new(metadata.address, metadata.rkey, metadata.length)
+    }
+}
+
+/// A bounds-checked range of a `RemoteBuffer`, from `RemoteBuffer::slice`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteSlice{
+    addr: u64,
+    rkey: u32,
+    length: u64,
+}
+
+impl RemoteSlice{
+    pub fn addr(&self) -> u64{
+        self.addr
+    }
+    pub fn rkey(&self) -> u32{
+        self.rkey
+    }
+    pub fn len(&self) -> u64{
+        self.length
+    }
+    pub fn is_empty(&self) -> bool{
+        self.length == 0
+    }
+}
+
+impl<'a> IbvMr<'a>{
+    /// The metadata to send to a peer so it can access the whole MR as a
+    /// `RemoteBuffer`.
+    pub fn metadata(&self) -> MrMetadata{
+        MrMetadata{
+            address: self.addr(),
+            rkey: self.rkey(),
+            length: self.length() as u64,
+        }
+    }
+}
+
+/// A signaled WR posted by `write` or `read`, whose completion arrives on
+/// the QP's send CQ. It borrows the QP and the local memory of the WR until
+/// the completion is reaped. Dropping it before then blocks until the WR
+/// completes, as the device may still access that memory.
+#[must_use = "the WR is only known to be done once its completion is reaped"]
+pub struct PendingWr<'a>{
+    wr_id: u64,
+    cq: IbvCq,
+    // Cleared once the completion is reaped or the WR is abandoned.
+    outstanding: bool,
+    _local: PhantomData<&'a mut [u8]>,
+}
+
+impl PendingWr<'_>{
+    // Posts a WR with `post` after tracking `wr_id`, which has to happen
+    // first so its completion cannot be reaped by another waiter before.
+    pub(crate) fn post(wr_id: u64, cq: &IbvCq, post: impl FnOnce() -> Result<()>) -> Result<Self>{
+        cq.pending_wrs().track(wr_id)?;
+        let pending = PendingWr{
+            wr_id,
+            cq: cq.clone(),
+            outstanding: true,
+            _local: PhantomData,
+        };
+        if let Err(err) = post(){
+            pending.abandon(None);
+            return Err(err);
+        }
+        Ok(pending)
+    }
+    pub fn wr_id(&self) -> u64{
+        self.wr_id
+    }
+    /// Blocks until the WR completes and returns its completion, or the
+    /// error it completed with.
+    ///
+    /// Handles waiting on the same CQ take turns polling it and pass each
+    /// other's completions on, so any number can be outstanding; posting a
+    /// second one with the `wr_id` of an outstanding one fails. Completions
+    /// of WRs without a handle, such as receives on a shared CQ, are kept
+    /// for `IbvCq::poll` and `IbvCq::wait`.
+    pub fn wait(mut self) -> Result<WorkCompletion>{
+        self.wait_for(None)
+    }
+    /// Like `wait`, but fails with ETIMEDOUT after `timeout`, after which
+    /// it can be called again.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<WorkCompletion>{
+        self.wait_for(Some(timeout))
+    }
+    pub(crate) fn wait_for(&mut self, timeout: Option<Duration>) -> Result<WorkCompletion>{
+        if !self.outstanding {
+            return Err(IbvError::invalid_argument("PendingWr::wait", format!("WR {} already completed", self.wr_id)));
+        }
+        let result = self.cq.pending_wrs().wait(&self.cq, self.wr_id, timeout);
+        if !matches!(&result, Err(err) if err.errno() == Some(libc::ETIMEDOUT)) {
+            self.outstanding = false;
+        }
+        result
+    }
+    // Stops waiting for the WR without blocking. Only for WRs that borrow
+    // no memory, or whose memory is kept alive by `keep` until the
+    // completion is reaped by another waiter.
+    pub(crate) fn abandon(mut self, keep: Option<Box<dyn Send>>){
+        self.outstanding = false;
+        self.cq.pending_wrs().release(self.wr_id, keep);
+    }
+}
+
+impl Drop for PendingWr<'_>{
+    fn drop(&mut self){
+        if self.outstanding {
+            if let Err(err) = self.wait_for(None){
+                warn!("Dropped WR {} failed: {}", self.wr_id, err);
+            }
+        }
+    }
+}
+
+// What is known about a WR with a `PendingWr`.
+enum Slot{
+    Waiting,
+    Done(WorkCompletion),
+    // The handle was abandoned before the WR completed; holds what has to
+    // stay alive until then, such as the buffer of a `PendingSend`.
+    Abandoned{ _keep: Box<dyn Send> },
+}
+
+#[derive(Default)]
+struct DemuxState{
+    slots: HashMap<u64, Slot>,
+    // Completions of WRs without a handle, such as receives, in the order
+    // they were reaped, until `IbvCq::poll` or `IbvCq::wait` hands them out.
+    untracked: VecDeque<WorkCompletion>,
+    // Whether a waiter is polling the CQ.
+    polling: bool,
+}
+
+impl DemuxState{
+    // Stores the completion of a WR with a handle. Returns false for other
+    // WRs, whose completion is left to the caller.
+    fn complete(&mut self, wc: &WorkCompletion) -> bool{
+        match self.slots.get(&wc.wr_id()){
+            Some(Slot::Waiting) => {
+                self.slots.insert(wc.wr_id(), Slot::Done(wc.clone()));
+            },
+            Some(Slot::Abandoned{ .. }) => {
+                self.slots.remove(&wc.wr_id());
+                if let Err(err) = wc.result(){
+                    warn!("Abandoned WR {} failed: {}", wc.wr_id(), err);
+                }
+            },
+            Some(Slot::Done(_)) | None => return false,
+        }
+        true
+    }
+    fn take_done(&mut self, wr_id: u64) -> Option<WorkCompletion>{
+        match self.slots.remove(&wr_id)?{
+            Slot::Done(wc) => Some(wc),
+            slot => {
+                self.slots.insert(wr_id, slot);
+                None
+            },
+        }
+    }
+    // Moves queued untracked completions to the front of `wcs`.
+    fn take_untracked(&mut self, wcs: &mut [WorkCompletion]) -> usize{
+        let taken = wcs.len().min(self.untracked.len());
+        for (slot, wc) in wcs.iter_mut().zip(self.untracked.drain(..taken)){
+            *slot = wc;
+        }
+        taken
+    }
+}
+
+// Hands the completions reaped from a CQ to the `PendingWr`s they belong
+// to, and the others to `IbvCq::poll` and `IbvCq::wait`. One waiter at a
+// time polls the CQ, the others sleep until it reaps something or stops
+// polling.
+#[derive(Default)]
+pub(crate) struct WrDemux{
+    state: Mutex<DemuxState>,
+    reaped: Condvar,
+}
+
+impl WrDemux{
+    fn track(&self, wr_id: u64) -> Result<()>{
+        let mut state = self.state.lock().unwrap();
+        if state.slots.contains_key(&wr_id) {
+            return Err(IbvError::invalid_argument("IbvQp::post_send", format!("WR {} is already outstanding with a handle on the send CQ", wr_id)));
+        }
+        state.slots.insert(wr_id, Slot::Waiting);
+        Ok(())
+    }
+    // Stops tracking `wr_id`, unless it is still outstanding and `keep`
+    // is given, in which case `keep` is dropped once it completes.
+    fn release(&self, wr_id: u64, keep: Option<Box<dyn Send>>){
+        let mut state = self.state.lock().unwrap();
+        match (state.slots.get(&wr_id), keep){
+            (Some(Slot::Waiting), Some(keep)) => {
+                state.slots.insert(wr_id, Slot::Abandoned{ _keep: keep });
+            },
+            (Some(Slot::Waiting | Slot::Done(_)), _) => {
+                state.slots.remove(&wr_id);
+            },
+            _ => {},
+        }
+    }
+    // Polls `cq` into `wcs` and stores the completions of tracked WRs,
+    // moving the others to the front of `wcs` and returning their number.
+    fn reap(&self, cq: &IbvCq, state: &mut DemuxState, wcs: &mut [WorkCompletion]) -> Result<usize>{
+        let polled = cq.poll_raw(wcs)?;
+        let mut untracked = 0;
+        for i in 0..polled{
+            if !state.complete(&wcs[i]) {
+                wcs.swap(untracked, i);
+                untracked += 1;
+            }
+        }
+        if polled > 0 {
+            self.reaped.notify_all();
+        }
+        Ok(untracked)
+    }
+    pub(crate) fn poll(&self, cq: &IbvCq, wcs: &mut [WorkCompletion]) -> Result<usize>{
+        let mut state = self.state.lock().unwrap();
+        let taken = state.take_untracked(wcs);
+        if taken > 0 {
+            return Ok(taken);
+        }
+        self.reap(cq, &mut state, wcs)
+    }
+    pub(crate) fn wait_untracked(&self, cq: &IbvCq, wcs: &mut [WorkCompletion], timeout: Option<Duration>) -> Result<usize>{
+        if wcs.is_empty() {
+            return Ok(0);
+        }
+        let taken = self.wait_until(cq, timeout, |state| Some(state.take_untracked(wcs)).filter(|&taken| taken > 0))?;
+        Ok(taken.unwrap_or(0))
+    }
+    fn wait(&self, cq: &IbvCq, wr_id: u64, timeout: Option<Duration>) -> Result<WorkCompletion>{
+        match self.wait_until(cq, timeout, |state| state.take_done(wr_id)){
+            Ok(Some(wc)) => {
+                wc.result()?;
+                Ok(wc)
+            },
+            Ok(None) => Err(IbvError::verb("ibv_get_cq_event", libc::ETIMEDOUT).with_wr_id(wr_id)),
+            Err(err) => {
+                self.release(wr_id, None);
+                Err(err)
+            },
+        }
+    }
+    // Waits until `take` returns something, polling the CQ while no other
+    // waiter does. Returns None if `timeout` passes first.
+    fn wait_until<T>(&self, cq: &IbvCq, timeout: Option<Duration>, mut take: impl FnMut(&mut DemuxState) -> Option<T>) -> Result<Option<T>>{
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut wcs: [WorkCompletion; 16] = Default::default();
+        let mut state = self.state.lock().unwrap();
+        loop{
+            if let Some(taken) = take(&mut state){
+                return Ok(Some(taken));
+            }
+            let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if remaining == Some(Duration::ZERO) {
+                return Ok(None);
+            }
+            if state.polling {
+                state = match remaining{
+                    Some(remaining) => self.reaped.wait_timeout(state, remaining).unwrap().0,
+                    None => self.reaped.wait(state).unwrap(),
+                };
+                continue;
+            }
+            state.polling = true;
+            drop(state);
+            // Also checks `take` after completions reaped by `IbvCq::poll`
+            // on another thread, whose event wakes this one.
+            let mut taken = None;
+            let polled = cq.wait_until(remaining, || {
+                let mut state = self.state.lock().unwrap();
+                let untracked = self.reap(cq, &mut state, &mut wcs)?;
+                state.untracked.extend(wcs[..untracked].iter().cloned());
+                taken = take(&mut state);
+                Ok(taken.is_some())
+            });
+            state = self.state.lock().unwrap();
+            state.polling = false;
+            self.reaped.notify_all();
+            polled?;
+            if let Some(taken) = taken{
+                return Ok(Some(taken));
+            }
+        }
+    }
+}
+
+impl IbvQp{
+    /// Writes `range` of `local` to `remote` with an RDMA write. The range
+    /// may not be longer than the remote slice.
+    pub fn write<'a>(&'a self, wr_id: u64, local: &'a IbvMr<'_>, range: Range<usize>, remote: RemoteSlice) -> Result<PendingWr<'a>>{
+        self.post_rdma("IbvQp::write", wr_id, IbvWrOpcode::RdmaWrite, IbvSge::from_mr(local, range)?, remote, None)
+    }
+    /// Like `write`, but also hands `imm_data` to the peer. The write
+    /// consumes a receive WR on the peer, whose completion carries the
+    /// immediate data once the written memory is visible.
+    pub fn write_with_imm<'a>(&'a self, wr_id: u64, local: &'a IbvMr<'_>, range: Range<usize>, remote: RemoteSlice, imm_data: u32) -> Result<PendingWr<'a>>{
+        self.post_rdma("IbvQp::write_with_imm", wr_id, IbvWrOpcode::RdmaWriteWithImm, IbvSge::from_mr(local, range)?, remote, Some(imm_data))
+    }
+    /// Reads `remote` into `range` of `local` with an RDMA read. The peer's
+    /// MR has to allow remote reads.
+    pub fn read<'a>(&'a self, wr_id: u64, local: &'a mut IbvMr<'_>, range: Range<usize>, remote: RemoteSlice) -> Result<PendingWr<'a>>{
+        self.post_rdma("IbvQp::read", wr_id, IbvWrOpcode::RdmaRead, IbvSge::from_mr(local, range)?, remote, None)
+    }
+    fn post_rdma(&self, function: &'static str, wr_id: u64, opcode: IbvWrOpcode, local: IbvSge, remote: RemoteSlice, imm_data: Option<u32>) -> Result<PendingWr<'_>>{
+        if local.length() as u64 > remote.len() {
+            return Err(IbvError::invalid_argument(function, format!("{} local bytes for WR {} exceed the remote slice of {}", local.length(), wr_id, remote.len())));
+        }
+        let mut send_wr = IbvSendWr::new(wr_id, vec![local], opcode, IbvSendFlags::SIGNALED, remote.addr(), remote.rkey());
+        if let Some(imm_data) = imm_data{
+            send_wr.set_imm_data(imm_data);
+        }
+        PendingWr::post(wr_id, self.send_cq(), || self.ibv_post_send(send_wr))
+    }
+}
+
+/// A 64-bit value in a peer's MR, updated with RDMA atomics. The peer's MR
+/// has to allow `IbvAccessFlags::REMOTE_ATOMIC`.
+///
+/// The prior value returned by `compare_and_swap` and `fetch_add` lands in
+/// an 8-byte MR owned by the handle, so one operation can be outstanding
+/// per handle at a time.
+pub struct RemoteAtomicU64{
+    remote: RemoteSlice,
+    result: IbvMr<'static>,
+}
+
+impl RemoteAtomicU64{
+    /// Fails with `InvalidArgument` if `remote` is not exactly 8 bytes at an
+    /// 8-byte aligned address, and with `OperationNotSupported` if the
+    /// device has no atomic support or the QP was connected with a
+    /// `max_rd_atomic` of 0, so it cannot initiate atomics.
+    pub fn new(qp: &Qp<Rts>, remote: RemoteSlice) -> Result<Self>{
+        if remote.len() != 8 || !remote.addr().is_multiple_of(8) {
+            return Err(IbvError::invalid_argument("RemoteAtomicU64::new", "the target is not 8 bytes at an 8-byte aligned address"));
+        }
+        let pd = qp.pd();
+        let not_supported = |reason: String| IbvError::OperationNotSupported{
+            device: device_name(pd.context()),
+            operation: "remote atomics",
+            reason,
+        };
+        if !IbvDeviceAttr::query(pd.context())?.atomic_cap.is_supported() {
+            return Err(not_supported("the device reports no atomic capability".to_string()));
+        }
+        if qp.query()?.max_rd_atomic == 0 {
+            return Err(not_supported(format!("qp {} was connected with a max_rd_atomic of 0", qp.qp_num())));
+        }
+        let result = IbvMr::from_vec(pd, vec![0; 8], IbvAccessFlags::LOCAL_WRITE)?;
+        Ok(RemoteAtomicU64{
+            remote,
+            result,
+        })
+    }
+    pub fn remote(&self) -> RemoteSlice{
+        self.remote
+    }
+    /// Replaces the remote value with `swap` if it equals `compare`. The
+    /// pending operation yields the prior value, so the swap happened if
+    /// that equals `compare`.
+    pub fn compare_and_swap<'a>(&'a mut self, qp: &'a Qp<Rts>, wr_id: u64, compare: u64, swap: u64) -> Result<PendingAtomic<'a>>{
+        let send_wr = IbvSendWr::compare_and_swap(wr_id, self.result_sge()?, self.remote.addr(), self.remote.rkey(), compare, swap);
+        self.post(qp, send_wr)
+    }
+    /// Adds `add` to the remote value, wrapping on overflow. The pending
+    /// operation yields the prior value.
+    pub fn fetch_add<'a>(&'a mut self, qp: &'a Qp<Rts>, wr_id: u64, add: u64) -> Result<PendingAtomic<'a>>{
+        let send_wr = IbvSendWr::fetch_add(wr_id, self.result_sge()?, self.remote.addr(), self.remote.rkey(), add);
+        self.post(qp, send_wr)
+    }
+    /// Writes `value` to the remote value with an ATOMIC_WRITE, which is
+    /// never seen half-written by the peer. Devices without atomic write
+    /// support fail the WR. The pending operation yields `value`.
+    pub fn store<'a>(&'a mut self, qp: &'a Qp<Rts>, wr_id: u64, value: u64) -> Result<PendingAtomic<'a>>{
+        self.result.as_mut_slice().copy_from_slice(&value.to_ne_bytes());
+        let send_wr = IbvSendWr::new(wr_id, vec![self.result_sge()?], IbvWrOpcode::AtomicWrite, IbvSendFlags::SIGNALED, self.remote.addr(), self.remote.rkey());
+        self.post(qp, send_wr)
+    }
+    fn result_sge(&self) -> Result<IbvSge>{
+        IbvSge::from_mr(&self.result, 0..8)
+    }
+    fn post<'a>(&'a mut self, qp: &'a Qp<Rts>, send_wr: IbvSendWr) -> Result<PendingAtomic<'a>>{
+        let pending = PendingWr::post(send_wr.wr_id(), qp.send_cq(), || qp.post_send(send_wr))?;
+        Ok(PendingAtomic{
+            pending,
+            result: &self.result,
+        })
+    }
+}
+
+/// An atomic posted by `RemoteAtomicU64`, borrowing its result buffer until
+/// the operation completes, see `PendingWr`.
+#[must_use = "the prior value is only available once the completion is reaped"]
+pub struct PendingAtomic<'a>{
+    pending: PendingWr<'a>,
+    result: &'a IbvMr<'static>,
+}
+
+impl PendingAtomic<'_>{
+    pub fn wr_id(&self) -> u64{
+        self.pending.wr_id()
+    }
+    /// Blocks until the operation completes and returns the prior remote
+    /// value, see `PendingWr::wait`.
+    pub fn wait(self) -> Result<u64>{
+        self.pending.wait()?;
+        Ok(prior_value(self.result))
+    }
+    /// Like `wait`, but fails with ETIMEDOUT after `timeout`, after which
+    /// it can be called again.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<u64>{
+        self.pending.wait_timeout(timeout)?;
+        Ok(prior_value(self.result))
+    }
+}
+
+// The responder returns the value in host order.
+fn prior_value(result: &IbvMr) -> u64{
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(result.as_slice());
+    u64::from_ne_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn slice_is_bounds_checked(){
+        let remote = RemoteBuffer::new(0x1000, 7, 64);
+        let slice = remote.slice(8..24).unwrap();
+        assert_eq!((slice.addr(), slice.rkey(), slice.len()), (0x1008, 7, 16));
+        assert!(remote.slice(0..65).is_err());
+        assert!(matches!(remote.slice(32..16), Err(IbvError::InvalidArgument{ .. })));
+    }
+
+    #[test]
+    fn track_refuses_outstanding_wr_id(){
+        let demux = WrDemux::default();
+        demux.track(1).unwrap();
+        assert!(matches!(demux.track(1), Err(IbvError::InvalidArgument{ .. })));
+        demux.release(1, Some(Box::new(())));
+        assert!(demux.track(1).is_err());
+        demux.state.lock().unwrap().complete(&WorkCompletion::with_wr_id(1));
+        demux.track(1).unwrap();
+    }
+
+    #[test]
+    fn completions_go_to_their_handle_or_the_untracked_queue(){
+        let mut state = DemuxState::default();
+        state.slots.insert(1, Slot::Waiting);
+        for wr_id in [2, 1, 3]{
+            let wc = WorkCompletion::with_wr_id(wr_id);
+            if !state.complete(&wc) {
+                state.untracked.push_back(wc);
+            }
+        }
+        assert_eq!(state.take_done(1).map(|wc| wc.wr_id()), Some(1));
+        assert!(state.take_done(1).is_none());
+        let mut wcs: [WorkCompletion; 1] = Default::default();
+        assert_eq!(state.take_untracked(&mut wcs), 1);
+        assert_eq!(wcs[0].wr_id(), 2);
+        assert_eq!(state.take_untracked(&mut wcs), 1);
+        assert_eq!(wcs[0].wr_id(), 3);
+        assert_eq!(state.take_untracked(&mut wcs), 0);
+    }
+
+    #[test]
+    fn slice_rejects_wrapping_peer_address(){
+        let remote = RemoteBuffer::new(u64::MAX - 8, 7, 64);
+        assert!(remote.slice(0..8).is_ok());
+        assert!(remote.slice(4..16).is_err());
+    }
+}
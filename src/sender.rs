@@ -1,31 +1,37 @@
-use std::{io::{Read, Write}, net::{IpAddr, TcpStream}};
+use std::{collections::HashMap, io::{Read, Write}, net::{IpAddr, SocketAddr, TcpStream}};
 use log::info;
 
-use crate::{Family, IbvAccessFlags, IbvDevice, IbvMr, IbvPd, IbvQp, IbvRecvWr, IbvSge, LookUpBy, MrMetadata, QpMetadata, SocketComm, SocketCommCommand};
+use crate::{ConnectParams, Family, IbvAccessFlags, IbvDevice, IbvMr, IbvPd, IbvRecvWr, Init, LookUpBy, MrMetadata, Qp, QpBuilder, QpMetadata, Rts, SocketComm, SocketCommCommand};
 
 pub struct Sender{
     device: IbvDevice,
     receiver_socket_address: IpAddr,
     receiver_socket_port: u16,
     sender_metadata: MrMetadata,
-    sender_metadata_mr: IbvMr,
+    sender_metadata_mr: IbvMr<'static>,
     pub receiver_metadata_address: u64,
     pub receiver_metadata_rkey: u32,
     pub pd: IbvPd,
-    pub qp_list: Vec<IbvQp>,
+    // None while the QP is recovered, or if recovering it failed.
+    qp_list: Vec<Option<Qp<Rts>>>,
+    // QPs between `recover_qp` and `reconnect_qp`, by index.
+    recovering_qps: HashMap<usize, Qp<Init>>,
+    remote_qp_metadata_list: Vec<QpMetadata>,
+    qp_params_list: Vec<ConnectParams>,
     num_qps: u32,
-    family: Family
+    family: Family,
+    connect_params: ConnectParams,
 }
 
 impl Sender {
     pub fn new(look_up_by: LookUpBy, receiver_socket_address: IpAddr, receiver_socket_port: u16, num_qps: u32, family: Family) -> anyhow::Result<Sender> {
         let device = IbvDevice::new(look_up_by)?;
         info!("Sender created device");
-        let pd = IbvPd::new(&device.context);
+        let pd = IbvPd::new(&device.context)?;
         info!("Sender created pd");
         let sender_metadata = MrMetadata::default();
-        let access_flags = IbvAccessFlags::LocalWrite.as_i32() | IbvAccessFlags::RemoteWrite.as_i32() | IbvAccessFlags::RemoteRead.as_i32();
-        let sender_metadata_mr = IbvMr::new(&pd, sender_metadata.addr(), MrMetadata::SIZE, access_flags);
+        let access_flags = IbvAccessFlags::local_and_remote();
+        let sender_metadata_mr = IbvMr::from_vec(&pd, vec![0; MrMetadata::SIZE], access_flags)?;
         info!("Sender created metadata memory region with addr: {}, rkey: {}", sender_metadata_mr.addr(), sender_metadata_mr.rkey());
         Ok(Sender{
             device,
@@ -37,10 +43,18 @@ impl Sender {
             sender_metadata_mr,
             pd,
             qp_list: Vec::new(),
+            recovering_qps: HashMap::new(),
+            remote_qp_metadata_list: Vec::new(),
+            qp_params_list: Vec::new(),
             num_qps,
-            family
+            family,
+            connect_params: ConnectParams::default(),
         })
     }
+    /// Parameters proposed to the receiver for every QP; applied on `connect`.
+    pub fn set_connect_params(&mut self, connect_params: ConnectParams) {
+        self.connect_params = connect_params;
+    }
     pub fn set_metadata_address(&mut self, addr: u64) {
         self.sender_metadata.address = addr;
     }
@@ -56,6 +70,54 @@ impl Sender {
     pub fn metadata_lkey(&self) -> u32 {
         self.sender_metadata_mr.lkey()
     }
+    /// The connected QP at `qp_idx`, or `None` while it is recovered or if
+    /// recovering it failed.
+    pub fn qp(&self, qp_idx: usize) -> Option<&Qp<Rts>> {
+        self.qp_list.get(qp_idx)?.as_ref()
+    }
+    /// Moves a QP that went into the ERROR state back to INIT and returns
+    /// its new PSN. Once the receiver did the same and both sides have the
+    /// other's new PSN, `reconnect_qp` connects the QP again. If recovery
+    /// fails the QP is dropped.
+    pub fn recover_qp(&mut self, qp_idx: usize) -> anyhow::Result<u32> {
+        let qp = self.qp_list.get_mut(qp_idx).and_then(Option::take).ok_or_else(|| anyhow::anyhow!("No connected QP with index {}", qp_idx))?;
+        let qp = qp.recover()?;
+        let psn = qp.psn();
+        info!("Sender recovered QP {} with psn {}", qp.qp_num(), psn);
+        self.recovering_qps.insert(qp_idx, qp);
+        Ok(psn)
+    }
+    /// Connects a QP taken to INIT by `recover_qp` to the remote QP, which
+    /// was recovered with `remote_psn`.
+    pub fn reconnect_qp(&mut self, qp_idx: usize, remote_psn: u32) -> anyhow::Result<()> {
+        let qp = self.recovering_qps.remove(&qp_idx).ok_or_else(|| anyhow::anyhow!("QP {} is not being recovered", qp_idx))?;
+        let remote_qp_metadata = &mut self.remote_qp_metadata_list[qp_idx];
+        remote_qp_metadata.psn = remote_psn;
+        let qp = qp.connect(remote_qp_metadata, &self.qp_params_list[qp_idx])?;
+        info!("Sender reconnected QP {}", qp.qp_num());
+        self.qp_list[qp_idx] = Some(qp);
+        Ok(())
+    }
+    /// Recovers a QP together with the receiver, which has to be waiting in
+    /// `Receiver::accept_recovery`. Both sides move their QP to INIT first
+    /// and then exchange the new PSNs in `RecoverQp` messages.
+    pub fn recover_qp_with_receiver(&mut self, qp_idx: usize) -> anyhow::Result<()> {
+        let psn = self.recover_qp(qp_idx)?;
+        let mut stream = TcpStream::connect(SocketAddr::new(self.receiver_socket_address, self.receiver_socket_port))?;
+        let socket_comm = SocketComm{
+            command: SocketCommCommand::RecoverQp(qp_idx as u32, psn),
+        };
+        stream.write_all(&bincode::serialize(&socket_comm)?)?;
+        let mut buffer = vec![0; 1024];
+        let len = stream.read(&mut buffer)?;
+        let socket_comm: SocketComm = bincode::deserialize(&buffer[..len])?;
+        match socket_comm.command {
+            SocketCommCommand::RecoverQp(remote_qp_idx, remote_psn) if remote_qp_idx as usize == qp_idx => {
+                self.reconnect_qp(qp_idx, remote_psn)
+            },
+            _ => Err(anyhow::anyhow!("Receiver did not recover QP {}", qp_idx)),
+        }
+    }
     pub fn connect(&mut self) -> anyhow::Result<()> {
         
         let send_address = if self.receiver_socket_address.is_ipv4() {
@@ -88,10 +150,14 @@ impl Sender {
             let gid_entry = self.device.gid_table.get_entry_by_index(qp_idx as usize, self.family.clone());
             if let Some((_ip_addr, gid_entry)) = gid_entry{
                 info!("Sender creating QP {}", qp_idx);
-                let qp = IbvQp::new(&self.pd, &self.device.context, gid_entry.gidx(), gid_entry.port());
+                let qp = QpBuilder::new(&self.pd)
+                    .gid_index(gid_entry.gidx())
+                    .port(gid_entry.port())
+                    .build_typed()?;
                 info!("Sender QP {} created", qp_idx);
-                qp.init(gid_entry.port)?;
+                let qp = qp.init(gid_entry.port)?;
                 info!("Sender QP {} initialized", qp_idx);
+                let local_params = self.connect_params.clamp_to(&self.device.attr()?, &self.device.port_attr(gid_entry.port())?);
                 let socket_comm = SocketComm{
                     command: crate::SocketCommCommand::InitQp(qp_idx, self.family.clone()),
                 };
@@ -102,7 +168,8 @@ impl Sender {
                 let socket_comm: SocketComm = bincode::deserialize(&buffer).unwrap();
                 if let SocketCommCommand::ConnectQp(remote_qp_metadata) = socket_comm.command {
                     info!("Sender received remote QP metadata: {:?}", remote_qp_metadata);
-                    qp.connect(&remote_qp_metadata)?;
+                    let params = local_params.negotiate(&remote_qp_metadata.params);
+                    let qp = qp.connect(&remote_qp_metadata, &params)?;
                     let subnet_id = gid_entry.subnet_id();
                     let interface_id = gid_entry.interface_id();
                     let qpn = qp.qp_num();
@@ -111,9 +178,12 @@ impl Sender {
                         subnet_id,
                         interface_id,
                         qpn,
-                        psn
+                        psn,
+                        params: local_params,
                     };
-                    self.qp_list.push(qp);
+                    self.qp_list.push(Some(qp));
+                    self.remote_qp_metadata_list.push(remote_qp_metadata);
+                    self.qp_params_list.push(params);
                     let sock_comm = SocketComm{
                         command: crate::SocketCommCommand::ConnectQp(qp_metadata),
                     };
@@ -129,11 +199,10 @@ impl Sender {
         let serialized = bincode::serialize(&socket_comm).unwrap();
         stream.write(&serialized).unwrap();
         info!("Sender sent stop command");
-        for qp in &self.qp_list{
-            let sge = IbvSge::new(self.metadata_addr(), MrMetadata::SIZE as u32, self.metadata_lkey());
-            let notify_wr = IbvRecvWr::new(0,sge,1);
+        for qp in self.qp_list.iter().flatten(){
+            let notify_wr = IbvRecvWr::from_mrs(0, &[(&self.sender_metadata_mr, 0..MrMetadata::SIZE)])?;
             info!("Sender posting receive");
-            qp.ibv_post_recv(notify_wr)?;
+            qp.post_recv(notify_wr)?;
             info!("Sender posted receive");
             qp.wait_for_event()?;
             info!("Sender received event");
@@ -0,0 +1,208 @@
+use std::{collections::{HashMap, VecDeque}, future::poll_fn, io, os::fd::{AsRawFd, RawFd}, task::{ready, Context, Poll}};
+use tokio::io::unix::AsyncFd;
+
+use crate::{IbvCompChannel, IbvCq, IbvError, IbvRecvWr, IbvSendWr, Qp, Result, Rts, WorkCompletion};
+
+/// Number of completions taken from a CQ per `ibv_poll_cq` call.
+const POLL_BATCH: usize = 16;
+
+struct ChannelFd(IbvCompChannel);
+
+impl AsRawFd for ChannelFd{
+    fn as_raw_fd(&self) -> RawFd{
+        self.0.fd()
+    }
+}
+
+fn io_error(verb: &'static str, err: io::Error) -> IbvError{
+    IbvError::verb(verb, err.raw_os_error().unwrap_or(libc::EIO))
+}
+
+/// Completions of one or more CQs sharing a completion channel, delivered
+/// through the tokio reactor instead of a blocking `ibv_get_cq_event`.
+///
+/// The channel is switched to non-blocking mode. A channel can only be
+/// registered with the reactor once, so create a single stream for all CQs
+/// attached to it.
+pub struct CompletionStream{
+    cqs: Vec<IbvCq>,
+    fd: AsyncFd<ChannelFd>,
+    ready: VecDeque<WorkCompletion>,
+}
+
+impl CompletionStream{
+    /// Must be called from within a tokio runtime.
+    pub fn new(cq: &IbvCq) -> Result<Self>{
+        CompletionStream::with_cqs(std::slice::from_ref(cq))
+    }
+    /// Creates a stream over several CQs, which must share one channel.
+    pub fn with_cqs(cqs: &[IbvCq]) -> Result<Self>{
+        let channel = match cqs.first().and_then(IbvCq::channel){
+            Some(channel) => channel.clone(),
+            None => return Err(IbvError::invalid_argument("CompletionStream::new", "the first CQ has no completion channel")),
+        };
+        if cqs.iter().any(|cq| cq.channel().map(IbvCompChannel::fd) != Some(channel.fd())) {
+            return Err(IbvError::invalid_argument("CompletionStream::new", "the CQs do not share one completion channel"));
+        }
+        channel.set_nonblocking()?;
+        let fd = AsyncFd::new(ChannelFd(channel)).map_err(|err| io_error("AsyncFd::new", err))?;
+        Ok(CompletionStream{
+            cqs: cqs.to_vec(),
+            fd,
+            ready: VecDeque::new(),
+        })
+    }
+    /// Waits for the next completion. Failed completions are returned as
+    /// they are, check `WorkCompletion::result`.
+    pub async fn next(&mut self) -> Result<WorkCompletion>{
+        poll_fn(|cx| self.poll_next(cx)).await
+    }
+    pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Result<WorkCompletion>>{
+        loop{
+            if let Some(wc) = self.ready.pop_front(){
+                return Poll::Ready(Ok(wc));
+            }
+            if self.drain()? > 0 {
+                continue;
+            }
+            // Arm, then poll again for completions that raced the arming.
+            for cq in &self.cqs{
+                cq.req_notify(false)?;
+            }
+            if self.drain()? > 0 {
+                continue;
+            }
+            let mut guard = ready!(self.fd.poll_read_ready(cx)).map_err(|err| io_error("poll_read_ready", err))?;
+            let mut events = 0;
+            while self.fd.get_ref().0.try_get_cq_event()?.is_some(){
+                events += 1;
+            }
+            if events == 0 {
+                guard.clear_ready();
+            }
+        }
+    }
+    fn drain(&mut self) -> Result<usize>{
+        let mut wcs: [WorkCompletion; POLL_BATCH] = Default::default();
+        let mut total = 0;
+        for cq in &self.cqs{
+            loop{
+                let polled = cq.poll(&mut wcs)?;
+                self.ready.extend(wcs[..polled].iter().cloned());
+                total += polled;
+                if polled < POLL_BATCH {
+                    break;
+                }
+            }
+        }
+        Ok(total)
+    }
+}
+
+// Removes the slot of a send when `post_send_and_wait` returns or its
+// future is dropped.
+struct SendGuard<'a>{
+    qp: &'a mut AsyncQp,
+    wr_id: u64,
+}
+
+impl Drop for SendGuard<'_>{
+    fn drop(&mut self){
+        self.qp.sends.remove(&self.wr_id);
+    }
+}
+
+/// A connected QP driven by the tokio reactor.
+///
+/// Completions are routed by wr_id: those matching an outstanding
+/// `post_send_and_wait` complete it, all others are handed out by `recv`.
+/// wr_ids of outstanding sends must therefore not be reused by receives.
+pub struct AsyncQp{
+    qp: Qp<Rts>,
+    streams: Vec<CompletionStream>,
+    sends: HashMap<u64, Option<WorkCompletion>>,
+    recvs: VecDeque<WorkCompletion>,
+}
+
+impl AsyncQp{
+    /// Must be called from within a tokio runtime.
+    pub fn new(qp: Qp<Rts>) -> Result<Self>{
+        let send_cq = qp.send_cq().clone();
+        let recv_cq = qp.recv_cq().clone();
+        let shares_channel = send_cq.channel().map(IbvCompChannel::fd) == recv_cq.channel().map(IbvCompChannel::fd);
+        let streams = if send_cq == recv_cq {
+            vec![CompletionStream::new(&send_cq)?]
+        } else if shares_channel {
+            vec![CompletionStream::with_cqs(&[send_cq, recv_cq])?]
+        } else {
+            vec![CompletionStream::new(&send_cq)?, CompletionStream::new(&recv_cq)?]
+        };
+        Ok(AsyncQp{
+            qp,
+            streams,
+            sends: HashMap::new(),
+            recvs: VecDeque::new(),
+        })
+    }
+    pub fn qp(&self) -> &Qp<Rts>{
+        &self.qp
+    }
+    pub fn into_inner(self) -> Qp<Rts>{
+        self.qp
+    }
+    pub fn post_recv(&self, recv_wr: IbvRecvWr) -> Result<()>{
+        self.qp.post_recv(recv_wr)
+    }
+    /// Posts a signaled send and waits for its completion.
+    ///
+    /// Dropping the future before it resolves does not cancel the send;
+    /// the WR stays posted and its completion is handed out by `recv`.
+    pub async fn post_send_and_wait(&mut self, mut send_wr: IbvSendWr) -> Result<WorkCompletion>{
+        let wr_id = send_wr.wr_id();
+        send_wr.set_signaled();
+        self.sends.insert(wr_id, None);
+        let guard = SendGuard{
+            qp: self,
+            wr_id,
+        };
+        guard.qp.qp.post_send(send_wr)?;
+        poll_fn(|cx| loop{
+            if let Some(Some(_)) = guard.qp.sends.get(&wr_id){
+                return Poll::Ready(Ok(()));
+            }
+            ready!(guard.qp.poll_completions(cx))?;
+        }).await?;
+        let wc = guard.qp.sends.get_mut(&wr_id).and_then(Option::take).expect("send completion is set");
+        wc.result()?;
+        Ok(wc)
+    }
+    /// Waits for the next receive completion. Receive WRs have to be posted
+    /// beforehand with `post_recv`.
+    pub async fn recv(&mut self) -> Result<WorkCompletion>{
+        let wc = poll_fn(|cx| loop{
+            if let Some(wc) = self.recvs.pop_front(){
+                return Poll::Ready(Ok(wc));
+            }
+            if let Err(err) = ready!(self.poll_completions(cx)){
+                return Poll::Ready(Err(err));
+            }
+        }).await?;
+        wc.result()?;
+        Ok(wc)
+    }
+    // Moves the next available completion from any stream to the send or
+    // receive side.
+    fn poll_completions(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>>{
+        for stream in &mut self.streams{
+            if let Poll::Ready(wc) = stream.poll_next(cx){
+                let wc = wc?;
+                match self.sends.get_mut(&wc.wr_id()){
+                    Some(slot @ None) => *slot = Some(wc),
+                    _ => self.recvs.push_back(wc),
+                }
+                return Poll::Ready(Ok(()));
+            }
+        }
+        Poll::Pending
+    }
+}